@@ -0,0 +1,60 @@
+#version 330 core
+
+in vec3 TexCoords;
+flat in vec4 fg;
+flat in vec4 bg;
+
+layout(location = 0, index = 0) out vec4 color;
+layout(location = 0, index = 1) out vec4 alphaMask;
+
+uniform int renderingPass;
+uniform sampler2DArray mask;
+
+// distance stored at the edges of the field, in field texels
+uniform float sdfRange;
+// screen pixels per field texel
+uniform float sdfScale;
+
+// widths in screen pixels, 0 to disable
+uniform vec3 outlineColor;
+uniform float outlineWidth;
+uniform vec3 glowColor;
+uniform float glowWidth;
+
+void main() {
+	if (renderingPass == 0) {
+		if (bg.a == 0.0) {
+			discard;
+		}
+
+		alphaMask = vec4(bg.a);
+		color = bg;
+		return;
+	}
+
+	// signed distance to the outline in screen pixels, positive inside
+	float field = texture(mask, TexCoords).r;
+	float dist = (field - 0.5) * 2.0 * sdfRange * sdfScale;
+
+	float fill = clamp(dist + 0.5, 0.0, 1.0);
+	float outline = 0.0;
+	if (outlineWidth > 0.0) {
+		outline = clamp(dist + outlineWidth + 0.5, 0.0, 1.0);
+	}
+	float glow = 0.0;
+	if (glowWidth > 0.0) {
+		glow = 1.0 - smoothstep(0.0, glowWidth, -(dist + outlineWidth));
+	}
+
+	// fill over outline over glow, premultiplied
+	vec4 result = vec4(glowColor * glow, glow);
+	result = vec4(outlineColor, 1.0) * outline + result * (1.0 - outline);
+	result = vec4(fg.rgb, 1.0) * fill + result * (1.0 - fill);
+
+	if (result.a == 0.0) {
+		discard;
+	}
+
+	color = vec4(result.rgb / result.a, result.a);
+	alphaMask = vec4(result.a);
+}
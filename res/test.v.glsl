@@ -1,13 +0,0 @@
-#version 330 core
-
-layout (location = 0) in vec3 pos;
-layout (location = 1) in vec2 texCoord;
-/* layout (location = 1) in vec3 vColor; */
-
-out vec2 TexCoord;
-/* out vec3 ourColor; */
-
-void main() {
-	gl_Position = vec4(pos, 1.0);
-	TexCoord = texCoord;
-}
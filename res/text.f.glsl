@@ -1,6 +1,6 @@
 #version 330 core
 
-in vec2 TexCoords;
+in vec3 TexCoords;
 flat in vec4 fg;
 flat in vec4 bg;
 
@@ -8,9 +8,12 @@ layout(location = 0, index = 0) out vec4 color;
 layout(location = 0, index = 1) out vec4 alphaMask;
 
 #define COLORED 1
+#define DECORATION 4
+#define CURLY 8
+#define FILL 16
 
 uniform int renderingPass;
-uniform sampler2D mask;
+uniform sampler2DArray mask;
 
 void main() {
 	if (renderingPass == 0) {
@@ -18,25 +21,52 @@ void main() {
 			discard;
 		}
 
-		alphaMask = vec4(1.0);
-		color = vec4(bg.rgb * bg.a, bg.a);
+		alphaMask = vec4(bg.a);
+		color = bg;
 		return;
 	}
 
-	float colored = fg.a;
+	int flags = int(fg.a);
 
-	if (int(colored) == COLORED) {
+	// only a background
+	if ((flags & FILL) != 0) {
+		discard;
+	}
+
+	if ((flags & DECORATION) != 0) {
+		float coverage = 1.0;
+		if ((flags & CURLY) != 0) {
+			// a sine wave filling the quad, its phase taken from the window
+			// so neighbouring pieces join up
+			float height = TexCoords.x;
+			float thickness = TexCoords.z;
+			float amplitude = (height - thickness) / 2.0;
+			float k = 3.14159265 / (4.0 * max(amplitude, 0.5));
+			float x = gl_FragCoord.x * k;
+			float center = height / 2.0 + amplitude * sin(x);
+			float slope = amplitude * k * cos(x);
+			float dist = abs(TexCoords.y - center) / sqrt(1.0 + slope * slope);
+			coverage = clamp(thickness / 2.0 + 0.5 - dist, 0.0, 1.0);
+		}
+
+		color = vec4(fg.rgb, coverage);
+		alphaMask = vec4(coverage);
+		return;
+	}
+
+	if ((flags & COLORED) != 0) {
 		color = texture(mask, TexCoords);
 		alphaMask = vec4(color.a);
 
+		// atlases hold premultiplied colors
 		if (color.a != 0.0) {
 			color.rgb = vec3(color.rgb / color.a);
 		}
-
-		color = vec4(color.rgb, 1.0);
 	} else {
+		// per channel coverage for subpixel text. grayscale atlases are
+		// swizzled so every channel reads the same value
 		vec3 textColor = texture(mask, TexCoords).rgb;
-		alphaMask = vec4(textColor, textColor.r);
-		color = vec4(fg.rgb, 1.0);
+		alphaMask = vec4(textColor, textColor.g);
+		color = vec4(fg.rgb, textColor.g);
 	}
 }
@@ -3,19 +3,23 @@
 layout(location = 0) in vec2 gridCoords;
 layout(location = 1) in vec4 glyph;
 layout(location = 2) in vec4 uv;
-layout(location = 3) in vec4 fgColor;
-layout(location = 4) in vec4 bgColor;
+layout(location = 3) in float textureLayer;
+layout(location = 4) in vec4 fgColor;
+layout(location = 5) in vec4 bgColor;
 
-out vec2 TexCoords;
+out vec3 TexCoords;
 flat out vec4 fg;
 flat out vec4 bg;
 
 uniform vec2 cellDim;
 uniform vec4 projection;
 
-uniform int renderPass;
+uniform int renderingPass;
+uniform sampler2DArray mask;
 
 #define WIDE_CHAR 2
+#define DECORATION 4
+#define FILL 16
 
 void main() {
 	vec2 projectionOffset = projection.xy;
@@ -27,24 +31,33 @@ void main() {
 
 	vec2 cellPosition = cellDim * gridCoords;
 
-	fg = vec4(fgColor.rgb / 255.0, fgColor.a);
+	// the fragment shader gets every flag but this one
+	int flags = int(fgColor.a);
+	fg = vec4(fgColor.rgb / 255.0, float(flags & ~WIDE_CHAR));
 	bg = bgColor / 255.0;
 
 	float occupiedCells = 1;
-	if ((int(fg.a) >= WIDE_CHAR)) {
+	if ((flags & WIDE_CHAR) != 0) {
 		occupiedCells = 2;
-		fg.a = round(fg.a - WIDE_CHAR);
 	}
 
-	if (renderPass == 0) {
+	if (renderingPass == 0) {
 		vec2 backgroundDim = cellDim;
 		backgroundDim.x *= occupiedCells;
+		vec2 backgroundOffset = vec2(0.0);
 
-		vec2 finalPosition = cellPosition + backgroundDim * position;
+		// placed like a glyph rather than filling the cell
+		if ((flags & FILL) != 0) {
+			backgroundDim = glyph.zw;
+			backgroundOffset = vec2(glyph.x, cellDim.y - glyph.y);
+		}
+
+		vec2 finalPosition =
+			cellPosition + backgroundOffset + backgroundDim * position;
 		gl_Position = 
 			vec4(projectionOffset + projectionScale * finalPosition, 0.0, 1.0);
 
-		TexCoords = vec2(0, 0);
+		TexCoords = vec3(0, 0, 0);
 	} else {
 		vec2 glyphSize = glyph.zw;
 		vec2 glyphOffset = glyph.xy;
@@ -54,8 +67,17 @@ void main() {
 		gl_Position = 
 			vec4(projectionOffset + projectionScale * finalPosition, 0.0, 1.0);
 		
+		// decorations don't sample the atlas. instead they get their height,
+		// the distance from their top and their line thickness in pixels
+		if ((flags & DECORATION) != 0) {
+			TexCoords = vec3(glyphSize.y, glyphSize.y * position.y, uv.x);
+			return;
+		}
+
+		// uvs are in pixels so they stay valid when the atlas grows
 		vec2 uvOffset = uv.xy;
 		vec2 uvSize = uv.zw;
-		TexCoords = uvOffset + position * uvSize;
+		vec2 atlasSize = vec2(textureSize(mask, 0).xy);
+		TexCoords = vec3((uvOffset + position * uvSize) / atlasSize, textureLayer);
 	}
 }
@@ -0,0 +1,322 @@
+use std::ops::Range;
+
+use unicode_bidi::{BidiInfo, Level};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Commands that do something when run, anything else is echoed to the log
+/// and reported as an unknown command
+pub const COMMANDS: &[&str] = &["quit"];
+
+/// Text being typed along with a cursor that moves a grapheme at a time
+///
+/// the cursor sits on a grapheme, or past the end of the text, and moves
+/// between them in the order they're displayed, so left always goes left
+/// even in right to left text. selections cover a logical range, which may
+/// not be contiguous on screen where directions change
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandLine {
+    text: String,
+    // byte offset into `text`, always on a grapheme boundary
+    cursor: usize,
+    // where the selection started, it runs from here to the cursor
+    anchor: Option<usize>,
+}
+
+impl CommandLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// bytes of the first word, the command, if it isn't one of `COMMANDS`
+    pub fn unknown_command(&self) -> Option<Range<usize>> {
+        let start = self.text.len() - self.text.trim_start().len();
+        let end = self.text[start..]
+            .find(char::is_whitespace)
+            .map_or(self.text.len(), |i| start + i);
+        let command = &self.text[start..end];
+        (!command.is_empty() && !COMMANDS.contains(&command))
+            .then_some(start..end)
+    }
+
+    /// selected bytes of the text, if any are
+    pub fn selection(&self) -> Option<Range<usize>> {
+        let anchor = self.anchor?;
+        let range = anchor.min(self.cursor)..anchor.max(self.cursor);
+        (!range.is_empty()).then_some(range)
+    }
+
+    /// graphemes and their byte offsets in display order, with an empty one
+    /// at the end of the text wherever the cursor goes past it
+    pub fn visual_graphemes(&self) -> Vec<(usize, &str)> {
+        let bidi = BidiInfo::new(&self.text, None);
+
+        // the command line is a single line even if it holds more than one
+        // paragraph
+        let mut levels = Vec::with_capacity(self.text.len());
+        for paragraph in &bidi.paragraphs {
+            let range = paragraph.range.clone();
+            let reordered = bidi.reordered_levels(paragraph, range.clone());
+            levels.extend_from_slice(&reordered[range]);
+        }
+        let base = bidi
+            .paragraphs
+            .first()
+            .map_or_else(Level::ltr, |paragraph| paragraph.level);
+
+        let mut graphemes: Vec<_> = self.text.grapheme_indices(true).collect();
+        graphemes.push((self.text.len(), ""));
+        let levels: Vec<_> = graphemes
+            .iter()
+            .map(|&(i, _)| levels.get(i).copied().unwrap_or(base))
+            .collect();
+
+        BidiInfo::reorder_visual(&levels)
+            .into_iter()
+            .map(|i| graphemes[i])
+            .collect()
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.delete_selection();
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+
+        // a combining mark or joiner may have merged with the grapheme
+        // before it, so the cursor could be in the middle of one now
+        self.cursor = self.next_boundary(self.prev_boundary(self.cursor));
+    }
+
+    /// remove the selection, or the grapheme before the cursor
+    pub fn backspace(&mut self) {
+        if self.delete_selection() {
+            return;
+        }
+
+        let start = self.prev_boundary(self.cursor);
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// remove the selection, or the grapheme under the cursor
+    pub fn delete(&mut self) {
+        if self.delete_selection() {
+            return;
+        }
+
+        let end = self.next_boundary(self.cursor);
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    /// move to the grapheme displayed to the left, extending the selection
+    /// if `select` is set or clearing it otherwise
+    pub fn move_left(&mut self, select: bool) {
+        self.move_visually(select, -1);
+    }
+
+    pub fn move_right(&mut self, select: bool) {
+        self.move_visually(select, 1);
+    }
+
+    /// move to the start of the text, which is on the right if it starts
+    /// with right to left text
+    pub fn move_home(&mut self, select: bool) {
+        self.move_to(0, select);
+    }
+
+    pub fn move_end(&mut self, select: bool) {
+        self.move_to(self.text.len(), select);
+    }
+
+    /// move to the grapheme starting at byte `offset`, like one that was
+    /// clicked on
+    pub fn move_to(&mut self, offset: usize, select: bool) {
+        if !select {
+            self.anchor = None;
+        } else if self.anchor.is_none() {
+            self.anchor = Some(self.cursor);
+        }
+        self.cursor = offset;
+    }
+
+    /// the finished command, leaving the line empty
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        self.anchor = None;
+        std::mem::take(&mut self.text)
+    }
+
+    fn move_visually(&mut self, select: bool, step: isize) {
+        let graphemes = self.visual_graphemes();
+        let index = graphemes
+            .iter()
+            .position(|&(i, _)| i == self.cursor)
+            .unwrap_or(0);
+        let index = index.saturating_add_signed(step).min(graphemes.len() - 1);
+        self.move_to(graphemes[index].0, select);
+    }
+
+    // returns whether there was anything selected
+    fn delete_selection(&mut self) -> bool {
+        let selection = self.selection();
+        self.anchor = None;
+
+        match selection {
+            Some(range) => {
+                self.cursor = range.start;
+                self.text.replace_range(range, "");
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn prev_boundary(&self, offset: usize) -> usize {
+        self.text[..offset]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self, offset: usize) -> usize {
+        self.text[offset..]
+            .graphemes(true)
+            .next()
+            .map_or(offset, |grapheme| offset + grapheme.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "ab", a space, alef and bet, a space and "cd", with the Hebrew taking
+    // two bytes a letter
+    const MIXED: &str = "ab \u{5d0}\u{5d1} cd";
+
+    fn typed(text: &str) -> CommandLine {
+        let mut command_line = CommandLine::new();
+        text.chars().for_each(|c| command_line.insert(c));
+        command_line
+    }
+
+    fn offsets(command_line: &CommandLine) -> Vec<usize> {
+        let graphemes = command_line.visual_graphemes();
+        graphemes.into_iter().map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn visual_order() {
+        assert_eq!(offsets(&typed("abc")), [0, 1, 2, 3]);
+        // bet is shown before alef
+        assert_eq!(offsets(&typed(MIXED)), [0, 1, 2, 5, 3, 7, 8, 9, 10]);
+        // the end of right to left text is on the left
+        assert_eq!(offsets(&typed("\u{5d0}\u{5d1}")), [4, 2, 0]);
+        assert_eq!(offsets(&CommandLine::new()), [0]);
+    }
+
+    #[test]
+    fn moves_in_display_order() {
+        let mut command_line = typed(MIXED);
+        command_line.move_home(false);
+        let mut visited = vec![command_line.cursor()];
+        for _ in 0..10 {
+            command_line.move_right(false);
+            visited.push(command_line.cursor());
+        }
+        // stops at the end
+        assert_eq!(visited, [0, 1, 2, 5, 3, 7, 8, 9, 10, 10, 10]);
+
+        for _ in 0..3 {
+            command_line.move_left(false);
+        }
+        assert_eq!(command_line.cursor(), 7);
+        command_line.move_left(false);
+        assert_eq!(command_line.cursor(), 3);
+    }
+
+    #[test]
+    fn left_goes_left_in_right_to_left_text() {
+        let mut command_line = typed("\u{5d0}\u{5d1}");
+        command_line.move_home(false);
+        assert_eq!(command_line.cursor(), 0);
+        // the start is on the right
+        command_line.move_right(false);
+        assert_eq!(command_line.cursor(), 0);
+        command_line.move_left(false);
+        assert_eq!(command_line.cursor(), 2);
+        command_line.move_left(false);
+        command_line.move_left(false);
+        assert_eq!(command_line.cursor(), 4);
+    }
+
+    #[test]
+    fn home_and_end() {
+        let mut command_line = typed(MIXED);
+        assert_eq!(command_line.cursor(), MIXED.len());
+        command_line.move_home(false);
+        assert_eq!(command_line.cursor(), 0);
+        assert_eq!(command_line.selection(), None);
+
+        command_line.move_end(true);
+        assert_eq!(command_line.cursor(), MIXED.len());
+        assert_eq!(command_line.selection(), Some(0..MIXED.len()));
+        command_line.move_home(false);
+        assert_eq!(command_line.selection(), None);
+    }
+
+    #[test]
+    fn selection_across_a_direction_change() {
+        let mut command_line = typed(MIXED);
+        command_line.move_to(1, false);
+        // onto the space, then bet and alef
+        for _ in 0..3 {
+            command_line.move_right(true);
+        }
+        assert_eq!(command_line.cursor(), 3);
+        // bet comes after the cursor in the text, so it isn't selected even
+        // though it was moved over
+        assert_eq!(command_line.selection(), Some(1..3));
+
+        command_line.move_right(true);
+        assert_eq!(command_line.selection(), Some(1..7));
+        command_line.backspace();
+        assert_eq!(command_line.text(), "a cd");
+        assert_eq!(command_line.cursor(), 1);
+    }
+
+    #[test]
+    fn combining_marks_join_the_grapheme_before() {
+        let command_line = typed("e\u{301}");
+        assert_eq!(command_line.cursor(), 3);
+        assert_eq!(offsets(&command_line), [0, 3]);
+
+        let mut command_line = typed("ab");
+        command_line.move_to(1, false);
+        command_line.insert('\u{301}');
+        assert_eq!(command_line.text(), "a\u{301}b");
+        // still in front of the b
+        assert_eq!(command_line.cursor(), 3);
+        command_line.backspace();
+        assert_eq!(command_line.text(), "b");
+        assert_eq!(command_line.cursor(), 0);
+    }
+
+    #[test]
+    fn insert_replaces_the_selection() {
+        let mut command_line = typed("abc");
+        command_line.move_left(false);
+        command_line.move_home(true);
+        command_line.insert('x');
+        assert_eq!(command_line.text(), "xc");
+        assert_eq!(command_line.cursor(), 1);
+        assert_eq!(command_line.selection(), None);
+    }
+}
@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::io;
 use std::num::NonZeroU32;
 
 use glutin::{
@@ -14,23 +15,96 @@ use glutin::{
     },
     error::Result as GlutinResult,
     prelude::GlSurface,
-    surface::{Surface, SurfaceAttributesBuilder, SwapInterval, WindowSurface},
+    surface::{
+        Rect, Surface, SurfaceAttributesBuilder, SwapInterval, WindowSurface,
+    },
 };
 use raw_window_handle::{
     HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle,
 };
-use winit::{dpi::PhysicalSize, window::Window};
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::MouseScrollDelta,
+    window::Window,
+};
+
+use crate::command_line::CommandLine;
+use crate::renderer::{
+    Alignment, Cell, CellGrid, DamageRect, DamageTracker, Decoration,
+    FontStyle, MarkupError, RasterOptions, Renderer, Rgb, RichText, SdfStyle,
+    SpanStyle, TextSpan, DEFAULT_FONT_SIZE,
+};
+
+const COMMAND_COLOR: Rgb = Rgb::new(255, 255, 255);
+const CURSOR_COLOR: Rgb = Rgb::new(96, 96, 96);
+const SELECTION_COLOR: Rgb = Rgb::new(32, 64, 128);
+const HELP_COLOR: Rgb = Rgb::new(160, 160, 160);
+const HELP_TITLE: &str = "Command line";
+// key names are bold and commands underlined like links
+const HELP_MARKUP: &str = "Type a command and press [b]Enter[/b] to run it.\n\
+    [b]\u{2190}[/b] and [b]\u{2192}[/b] move the cursor a character at a \
+    time, [b]Home[/b] and [b]End[/b] jump to the start and end of the line, \
+    holding [b]Shift[/b] selects as it moves. [b]Backspace[/b] and \
+    [b]Delete[/b] remove the selection or a character by the cursor.\n\
+    [b]F1[/b] cycles hinting, [b]F2[/b] switches antialiasing, [b]F3[/b] \
+    toggles stem darkening, [b]F4 F5[/b] change gamma and [b]F6 F7[/b] \
+    contrast, for the monitor the window is on.\n\
+    [u]quit[/u] closes the window, commands that don't exist are \
+    [curl=#e04040]underlined[/curl].";
+const HELP_FONT_SIZE: f32 = 20.0;
+const ERROR_COLOR: Rgb = Rgb::new(224, 64, 64);
+// of the log console, which keeps this many lines to scroll back through
+const LOG_FONT_SIZE: f32 = 16.0;
+const LOG_LINES: usize = 1000;
+// rows scrolled per step of a mouse wheel
+const LOG_SCROLL_LINES: f32 = 3.0;
+// at the end of the command line
+const RUN_HINT: &str = "\u{21b5} run";
+// around the help text, in logical pixels
+const HELP_MARGIN: f32 = 8.0;
+const COMMAND_SDF_STYLE: SdfStyle = SdfStyle {
+    color: COMMAND_COLOR,
+    outline_width: 1.0,
+    outline_color: Rgb::new(32, 96, 192),
+    glow_width: 4.0,
+    glow_color: Rgb::new(16, 48, 96),
+};
 
-use crate::renderer::Renderer;
+// bands across the window, each redrawn on its own when it changes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Region {
+    Help,
+    Log,
+    Status,
+    CommandLine,
+}
 
 pub struct Display {
     context: PossiblyCurrentContext,
     surface: Surface<WindowSurface>,
     renderer: Renderer,
+    size: PhysicalSize<u32>,
+    // draw the command line from distance fields
+    sdf: bool,
+    help_text: RichText,
+    help_alignment: Alignment,
+    // a line of markup above the command line
+    status: RichText,
+    // fills the space between the help and the status line
+    log: CellGrid,
+    log_top: f32,
+    log_bottom: f32,
+    damage: DamageTracker,
+    // as it was last drawn
+    drawn_command: Option<CommandLine>,
 }
 
 impl Display {
-    pub fn new(window: &Window) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        window: &Window,
+        options: RasterOptions,
+        texture_array: bool,
+    ) -> Result<Self, Box<dyn Error>> {
         let gl_display = create_gl_display(window.raw_display_handle())?;
         let config = pick_gl_config(&gl_display, None)?;
         let context = create_gl_context(&gl_display, &config, None)?;
@@ -41,7 +115,9 @@ impl Display {
         )?;
 
         let context = context.make_current(&surface)?;
-        let mut renderer = Renderer::new(&context)?;
+        let scale_factor = window.scale_factor() as f32;
+        let mut renderer =
+            Renderer::new(&context, options, texture_array, scale_factor)?;
 
         let size = window.inner_size();
         let (width, height) = (size.width as f32, size.height as f32);
@@ -62,27 +138,332 @@ impl Display {
         // let version = CStr::from_ptr(gl::GetString(gl::VERSION) as *const i8).to_str()?;
         // println!("{}", version);
         // }
-        Ok(Self {
+        let mut display = Self {
             context,
             surface,
             renderer,
-        })
+            size,
+            sdf: false,
+            help_text: RichText::parse(HELP_MARKUP, help_style())
+                .expect("help markup is valid"),
+            help_alignment: Alignment::default(),
+            status: RichText::default(),
+            log: CellGrid::new(LOG_FONT_SIZE, LOG_LINES),
+            log_top: 0.0,
+            log_bottom: 0.0,
+            damage: DamageTracker::new(size.width as i32, size.height as i32),
+            drawn_command: None,
+        };
+        display.resize_log();
+
+        Ok(display)
+    }
+
+    /// called when the window moves to a monitor with a different DPI, before
+    /// the accompanying resize
+    pub fn set_scale_factor(&mut self, scale_factor: f64) {
+        self.make_current();
+        self.renderer.set_scale_factor(scale_factor as f32);
+        self.damage.damage_all();
+    }
+
+    pub fn set_raster_options(&mut self, options: RasterOptions) {
+        self.make_current();
+        self.renderer.set_raster_options(options);
+        self.damage.damage_all();
+    }
+
+    pub fn save_glyph_cache(&self) -> io::Result<()> {
+        self.renderer.save_glyph_cache()
+    }
+
+    pub fn set_atlas_budget(&mut self, bytes: usize) {
+        self.renderer.set_atlas_budget(bytes);
+    }
+
+    pub fn set_sdf(&mut self, sdf: bool) {
+        self.sdf = sdf;
+        self.damage(Region::CommandLine);
+    }
+
+    pub fn set_help_alignment(&mut self, alignment: Alignment) {
+        self.help_alignment = alignment;
+        self.resize_log();
+        self.damage.damage_all();
+    }
+
+    /// show `markup` above the command line, see `RichText` for the tags
+    pub fn set_status(&mut self, markup: &str) -> Result<(), MarkupError> {
+        self.status = RichText::parse(markup, help_style())?;
+        self.damage(Region::Status);
+        Ok(())
+    }
+
+    /// add a line of `markup` to the log console
+    pub fn log(&mut self, markup: &str) -> Result<(), MarkupError> {
+        let text = RichText::parse(markup, help_style())?;
+        self.log.push_line(&text.spans());
+        self.damage(Region::Log);
+        Ok(())
+    }
+
+    /// scroll the log console back through older lines, or forward for
+    /// negative deltas
+    pub fn scroll_log(&mut self, delta: MouseScrollDelta) {
+        let rows = match delta {
+            MouseScrollDelta::LineDelta(_, y) => y * LOG_SCROLL_LINES,
+            MouseScrollDelta::PixelDelta(position) => {
+                let scale_factor = self.renderer.scale_factor() as f64;
+                let (_, cell_height) =
+                    self.renderer.grid_cell_size(self.log.font_size());
+                (position.y / scale_factor) as f32 / cell_height
+            }
+        };
+        self.log.scroll(rows.round() as isize);
+        self.damage(Region::Log);
     }
 
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        self.size = size;
         self.renderer.resize(size.width as f32, size.height as f32);
+        self.resize_log();
+        self.damage.resize(size.width as i32, size.height as i32);
     }
 
-    pub fn draw(&self) {
+    /// draw everything again on the next `draw`, for when the window's
+    /// contents were lost
+    pub fn damage_all(&mut self) {
+        self.damage.damage_all();
+    }
+
+    /// redraw the parts of the window that changed since the last frame,
+    /// if any did
+    pub fn draw(&mut self, command: &CommandLine) {
+        if self.drawn_command.as_ref() != Some(command) {
+            self.damage(Region::CommandLine);
+            self.drawn_command = Some(command.clone());
+        }
+        // what's on screen is still up to date
+        if self.damage.frame().is_empty() {
+            return;
+        }
+
         self.make_current();
+        // the back buffer may hold an older frame than the one on screen,
+        // everything that changed since that one is drawn again
+        let Some(area) = self.damage.redraw_area(self.surface.buffer_age())
+        else {
+            return;
+        };
+        self.renderer.set_scissor(Some(area));
         self.renderer.clear();
-        self.renderer.draw();
-        unsafe {
-            gl::Finish();
+
+        let needs_drawing = |display: &Self, region| {
+            display.region_rect(region).intersects(&area)
+        };
+        if needs_drawing(self, Region::Help) {
+            self.draw_help();
         }
-        self.surface
-            .swap_buffers(&self.context)
-            .expect("failed to swap buffers.");
+        if needs_drawing(self, Region::Log) {
+            self.renderer
+                .draw_grid(&self.log, (HELP_MARGIN, self.log_top));
+        }
+        let y = self.command_line_y();
+        if needs_drawing(self, Region::Status) {
+            self.draw_status(y);
+        }
+        if needs_drawing(self, Region::CommandLine) {
+            self.draw_run_hint(y);
+            if self.sdf {
+                self.renderer.draw_sdf_text(
+                    command.text(),
+                    (0.0, y),
+                    DEFAULT_FONT_SIZE,
+                    &COMMAND_SDF_STYLE,
+                );
+            } else {
+                self.draw_command_line(command, y);
+            }
+        }
+        self.renderer.set_scissor(None);
+        self.renderer.end_frame();
+
+        self.swap_buffers();
+        self.damage.swapped();
+    }
+
+    /// byte offset of the grapheme in the command line at `position`, if
+    /// it's on the command line
+    pub fn command_line_offset(
+        &mut self,
+        position: PhysicalPosition<f64>,
+        command: &CommandLine,
+    ) -> Option<usize> {
+        let scale_factor = self.renderer.scale_factor() as f64;
+        let (x, y) = (
+            (position.x / scale_factor) as f32,
+            (position.y / scale_factor) as f32,
+        );
+        let top = self.command_line_y();
+        if y < top {
+            return None;
+        }
+
+        if self.sdf {
+            // laid out the same as the distance fields are drawn, just not
+            // scaled from them
+            let span = TextSpan::new(
+                command.text(),
+                FontStyle::REGULAR,
+                DEFAULT_FONT_SIZE,
+                COMMAND_COLOR,
+            );
+            let layout =
+                self.renderer
+                    .layout(&[span], f32::INFINITY, Alignment::Left);
+            return Some(layout.hit_test((x, y - top)));
+        }
+
+        let cell_width = self.renderer.cell_width();
+        let mut right = 0.0;
+        let cells = command_cells(command);
+        for &(offset, cell) in &cells {
+            right += cell.width() as f32 * cell_width;
+            if x < right {
+                return Some(offset);
+            }
+        }
+        // past the last cell
+        cells.last().map(|&(offset, _)| offset)
+    }
+
+    // the command line sits at the bottom of the window
+    fn command_line_y(&self) -> f32 {
+        let scale_factor = self.renderer.scale_factor();
+        let height = self.size.height as f32 / scale_factor;
+        height - self.renderer.line_height()
+    }
+
+    // right aligned on the command line
+    fn draw_run_hint(&mut self, y: f32) {
+        let scale_factor = self.renderer.scale_factor();
+        let (width, ..) = self.renderer.measure(
+            RUN_HINT,
+            FontStyle::REGULAR,
+            DEFAULT_FONT_SIZE,
+        );
+        let x = self.size.width as f32 / scale_factor - width - HELP_MARGIN;
+        self.renderer.draw_text(RUN_HINT, (x, y), HELP_COLOR);
+    }
+
+    // right above the command line at `y`
+    fn draw_status(&mut self, y: f32) {
+        let spans = self.status.spans();
+        let (_, height, _) = self.renderer.measure_spans(&spans);
+        self.renderer.draw_spans(&spans, (HELP_MARGIN, y - height));
+    }
+
+    fn draw_help(&mut self) {
+        self.renderer
+            .draw_text(HELP_TITLE, (HELP_MARGIN, 0.0), HELP_COLOR);
+        let y = self.renderer.line_height();
+        let width = self.help_width();
+        let spans = self.help_text.spans();
+        self.renderer.draw_wrapped(
+            &spans,
+            width,
+            self.help_alignment,
+            (HELP_MARGIN, y),
+        );
+    }
+
+    // the help text wraps to the window, so it reflows whenever its width
+    // or scale factor changes
+    fn help_width(&self) -> f32 {
+        let width = self.size.width as f32 / self.renderer.scale_factor();
+        width - 2.0 * HELP_MARGIN
+    }
+
+    // fit the log between the help and the status line, which always has
+    // room left for it
+    fn resize_log(&mut self) {
+        let scale_factor = self.renderer.scale_factor();
+        let width = self.help_width();
+        let spans = self.help_text.spans();
+        let (_, help_height, _) =
+            self.renderer
+                .measure_wrapped(&spans, width, self.help_alignment);
+        let top = self.renderer.line_height() + help_height + HELP_MARGIN;
+        let (_, status_height, _) =
+            self.renderer
+                .measure(" ", FontStyle::REGULAR, HELP_FONT_SIZE);
+        let bottom = self.command_line_y() - status_height - HELP_MARGIN;
+
+        let width = self.size.width as f32 / scale_factor - 2.0 * HELP_MARGIN;
+        let cell_size = self.renderer.grid_cell_size(LOG_FONT_SIZE);
+        self.log.resize(width, bottom - top, cell_size);
+        self.log_top = top;
+        self.log_bottom = bottom;
+    }
+
+    // top and bottom of `region` in logical pixels
+    fn region_bounds(&self, region: Region) -> (f32, f32) {
+        let height = self.size.height as f32 / self.renderer.scale_factor();
+        match region {
+            Region::Help => (0.0, self.log_top),
+            Region::Log => (self.log_top, self.log_bottom),
+            Region::Status => (self.log_bottom, self.command_line_y()),
+            Region::CommandLine => (self.command_line_y(), height),
+        }
+    }
+
+    // `region` in physical pixels, rounded out to whole ones
+    fn region_rect(&self, region: Region) -> DamageRect {
+        let scale_factor = self.renderer.scale_factor();
+        let (top, bottom) = self.region_bounds(region);
+        let top = (top * scale_factor).floor() as i32;
+        let bottom = (bottom * scale_factor).ceil() as i32;
+        DamageRect::new(0, top, self.size.width as i32, bottom - top)
+    }
+
+    fn damage(&mut self, region: Region) {
+        let rect = self.region_rect(region);
+        self.damage.damage(rect);
+    }
+
+    // lets the compositor know which parts of the window changed when EGL
+    // supports it, so it can skip the rest
+    fn swap_buffers(&self) {
+        let height = self.size.height as i32;
+        // these go up from the bottom of the window
+        let rects: Vec<_> = self
+            .damage
+            .frame()
+            .iter()
+            .map(|rect| {
+                Rect::new(
+                    rect.x,
+                    height - rect.y - rect.height,
+                    rect.width,
+                    rect.height,
+                )
+            })
+            .collect();
+        let result = match (&self.surface, &self.context) {
+            (Surface::Egl(surface), PossiblyCurrentContext::Egl(context)) => {
+                surface.swap_buffers_with_damage(context, &rects)
+            }
+            _ => self.surface.swap_buffers(&self.context),
+        };
+        result.expect("failed to swap buffers.");
+    }
+
+    fn draw_command_line(&mut self, command: &CommandLine, y: f32) {
+        let cells: Vec<_> = command_cells(command)
+            .into_iter()
+            .map(|(_, cell)| cell)
+            .collect();
+        self.renderer.draw_cells(&cells, (0.0, y));
     }
 
     fn make_current(&self) {
@@ -94,6 +475,41 @@ impl Display {
     }
 }
 
+// help and status text outside any tags
+fn help_style() -> SpanStyle {
+    SpanStyle::new(HELP_FONT_SIZE, HELP_COLOR)
+}
+
+// one grapheme per cell so wide characters line up with the cursor, in the
+// order they're displayed so the cursor moves in the direction of the arrow
+// keys. each comes with its byte offset. unknown commands get a squiggle
+fn command_cells(command: &CommandLine) -> Vec<(usize, Cell<'_>)> {
+    let cursor = command.cursor();
+    let selection = command.selection().unwrap_or_default();
+    let unknown = command.unknown_command().unwrap_or_default();
+    command
+        .visual_graphemes()
+        .into_iter()
+        .map(|(i, grapheme)| {
+            let bg = if i == cursor {
+                Some(CURSOR_COLOR)
+            } else if selection.contains(&i) {
+                Some(SELECTION_COLOR)
+            } else {
+                None
+            };
+            // the end of the text only has a cell for the cursor to go in
+            let grapheme = if grapheme.is_empty() { " " } else { grapheme };
+            let mut cell = Cell::new(grapheme, COMMAND_COLOR, bg);
+            if unknown.contains(&i) {
+                cell = cell
+                    .with_decoration(Decoration::UNDERCURL, Some(ERROR_COLOR));
+            }
+            (i, cell)
+        })
+        .collect()
+}
+
 fn create_gl_display(
     display_handle: RawDisplayHandle,
 ) -> GlutinResult<GlutinDisplay> {
@@ -136,7 +552,7 @@ fn create_gl_context(
         .with_context_api(ContextApi::OpenGl(Some(Version::new(3, 3))))
         .build(window_handle);
 
-    unsafe { gl_display.create_context(&gl_config, &context_attributes) }
+    unsafe { gl_display.create_context(gl_config, &context_attributes) }
 }
 
 fn create_gl_surface(
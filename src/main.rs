@@ -1,22 +1,106 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::mem;
 
 use winit::{
-    event::{Event, WindowEvent},
-    event_loop::EventLoop,
+    dpi::PhysicalPosition,
+    event::{
+        ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+        VirtualKeyCode, WindowEvent,
+    },
+    event_loop::{ControlFlow, EventLoop},
     window::Window,
 };
 
+mod command_line;
 mod display;
 mod renderer;
 
+use command_line::CommandLine;
 use display::Display;
+use renderer::{
+    escape_markup, Alignment, Antialiasing, Hinting, RasterOptions,
+};
+
+// severity of messages in the status line
+const ERROR_TAG: &str = "[bg=#a02828][color=#fff][b] ERROR [/b][/color][/bg]";
+const INFO_TAG: &str = "[bg=#285080][color=#fff][b] INFO [/b][/color][/bg]";
 
 fn main() -> Result<(), Box<dyn Error>> {
     let event_loop = EventLoop::new();
     let window = Window::new(&event_loop)?;
 
-    let mut display = Display::new(&window)?;
-    let mut command_buffer = Vec::with_capacity(128);
+    // subpixel text only looks right on RGB LCD panels, so it's opt-in
+    let antialiasing = if std::env::args().any(|arg| arg == "--subpixel") {
+        Antialiasing::Subpixel
+    } else {
+        Antialiasing::Grayscale
+    };
+
+    // glyph atlases add texture array layers instead of filling up, at the
+    // cost of every layer taking up a full size texture
+    let texture_array =
+        !std::env::args().any(|arg| arg == "--no-texture-array");
+
+    let hinting = std::env::args().find_map(|arg| {
+        match arg.strip_prefix("--hinting=")? {
+            "none" => Some(Hinting::None),
+            "vertical" => Some(Hinting::Vertical),
+            "full" => Some(Hinting::Full),
+            _ => None,
+        }
+    });
+    let gamma: Option<f32> = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--gamma=")?.parse().ok());
+    let contrast: Option<f32> = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--contrast=")?.parse().ok());
+    let defaults = RasterOptions::default();
+    let options = RasterOptions {
+        hinting: hinting.unwrap_or(defaults.hinting),
+        antialiasing,
+        gamma: gamma.unwrap_or(defaults.gamma),
+        contrast: contrast.unwrap_or(defaults.contrast),
+        stem_darkening: std::env::args().any(|arg| arg == "--stem-darkening"),
+    };
+
+    let mut display = Display::new(&window, options, texture_array)?;
+
+    // in MiB, glyphs that haven't been drawn recently are evicted once the
+    // atlases grow past this
+    let atlas_budget: Option<usize> = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--atlas-budget=")?.parse().ok());
+    if let Some(megabytes) = atlas_budget {
+        display.set_atlas_budget(megabytes << 20);
+    }
+
+    // scalable text with an outline and glow, to check on distance fields
+    display.set_sdf(std::env::args().any(|arg| arg == "--sdf"));
+
+    let alignment = std::env::args().find_map(|arg| {
+        match arg.strip_prefix("--help-align=")? {
+            "left" => Some(Alignment::Left),
+            "center" => Some(Alignment::Center),
+            "right" => Some(Alignment::Right),
+            "justify" => Some(Alignment::Justify),
+            _ => None,
+        }
+    });
+    if let Some(alignment) = alignment {
+        display.set_help_alignment(alignment);
+    }
+
+    let mut command_line = CommandLine::new();
+    let mut modifiers = ModifiersState::empty();
+    let mut mouse = PhysicalPosition::default();
+
+    // each monitor keeps the options they were changed to while the window
+    // was on it, the rest start out with the ones from the command line
+    let mut monitor_options = HashMap::new();
+    let mut monitor = window.current_monitor().and_then(|m| m.name());
+    let mut current_options = options;
+    // set along with `request_redraw`, any other redraw comes from the window
+    // system, which may have thrown away what was on screen
+    let mut redraw_requested = false;
 
     event_loop.run(move |event, _, control_flow| {
         control_flow.set_wait();
@@ -25,28 +109,197 @@ fn main() -> Result<(), Box<dyn Error>> {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => control_flow.set_exit(),
                 WindowEvent::ReceivedCharacter(c) => {
-                    received_char(c, &mut command_buffer)
+                    if let Some(command) = received_char(c, &mut command_line) {
+                        run_command(&command, &mut display, control_flow);
+                    }
+                    redraw_requested = true;
+                    window.request_redraw();
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => {
+                    if let Some(changed) = change_options(key, current_options)
+                    {
+                        show_options(&mut display, changed);
+                        current_options = changed;
+                        monitor_options.insert(monitor.clone(), changed);
+                        display.set_raster_options(changed);
+                    }
+                    pressed_key(key, modifiers, &mut command_line);
+                    redraw_requested = true;
+                    window.request_redraw();
+                }
+                WindowEvent::Moved(_) => {
+                    let current =
+                        window.current_monitor().and_then(|m| m.name());
+                    if current != monitor {
+                        current_options =
+                            *monitor_options.get(&current).unwrap_or(&options);
+                        monitor = current;
+                        display.set_raster_options(current_options);
+                        redraw_requested = true;
+                        window.request_redraw();
+                    }
+                }
+                WindowEvent::ModifiersChanged(state) => modifiers = state,
+                WindowEvent::CursorMoved { position, .. } => mouse = position,
+                // put the cursor where the command line was clicked
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Left,
+                    ..
+                } => {
+                    if let Some(offset) =
+                        display.command_line_offset(mouse, &command_line)
+                    {
+                        command_line.move_to(offset, modifiers.shift());
+                        redraw_requested = true;
+                        window.request_redraw();
+                    }
+                }
+                WindowEvent::MouseWheel { delta, .. } => {
+                    display.scroll_log(delta);
+                    redraw_requested = true;
+                    window.request_redraw();
                 }
                 WindowEvent::Resized(size) => display.resize(size),
+                WindowEvent::ScaleFactorChanged {
+                    scale_factor,
+                    new_inner_size,
+                } => {
+                    display.set_scale_factor(scale_factor);
+                    display.resize(*new_inner_size);
+                    redraw_requested = true;
+                    window.request_redraw();
+                }
                 _ => (),
             },
             Event::RedrawRequested(_) => {
-                display.draw();
+                if !mem::take(&mut redraw_requested) {
+                    display.damage_all();
+                }
+                display.draw(&command_line);
+            }
+            Event::LoopDestroyed => {
+                // the next run starts faster with the glyphs from this one,
+                // but works fine without them
+                if let Err(err) = display.save_glyph_cache() {
+                    println!("Failed to save the glyph cache: {}", err);
+                }
             }
             _ => (),
         }
     })
 }
 
-fn received_char(c: char, command_buffer: &mut Vec<char>) {
+// returns the command to run once enter is pressed
+fn received_char(c: char, command_line: &mut CommandLine) -> Option<String> {
     match c {
-        '\x08' => {
-            command_buffer.pop();
+        '\x08' => command_line.backspace(),
+        '\x7f' => command_line.delete(),
+        '\n' | '\r' => return Some(command_line.take()),
+        // anything else unprintable is a key handled by `pressed_key`
+        _ if c.is_control() => (),
+        _ => command_line.insert(c),
+    }
+    None
+}
+
+// see `command_line::COMMANDS`
+fn run_command(
+    command: &str,
+    display: &mut Display,
+    control_flow: &mut ControlFlow,
+) {
+    if !command.trim().is_empty() {
+        let echo = format!("[color=#8cf]>[/color] {}", escape_markup(command));
+        display.log(&echo).expect("log markup is valid");
+    }
+
+    match command.split_whitespace().next() {
+        Some("quit") => control_flow.set_exit(),
+        Some(name) => {
+            let status = format!(
+                "{} unknown command [color=#f80]{}[/color]",
+                ERROR_TAG,
+                escape_markup(name)
+            );
+            display.set_status(&status).expect("status markup is valid");
+            // errors stay in the log after the status changes
+            display.log(&status).expect("log markup is valid");
+        }
+        None => (),
+    }
+}
+
+// in the status line, along with the values that can be changed
+fn show_options(display: &mut Display, options: RasterOptions) {
+    let status = format!(
+        "{} [color=#8cf]{:?}[/color] hinting, [color=#8cf]{:?}[/color] \
+         antialiasing, gamma [color=#f80]{:.1}[/color], contrast \
+         [color=#f80]{:.1}[/color], stem darkening [color=#8cf]{}[/color]",
+        INFO_TAG,
+        options.hinting,
+        options.antialiasing,
+        options.gamma,
+        options.contrast,
+        if options.stem_darkening { "on" } else { "off" },
+    );
+    display.set_status(&status).expect("status markup is valid");
+}
+
+// keys that don't produce characters, shift extends the selection
+fn pressed_key(
+    key: VirtualKeyCode,
+    modifiers: ModifiersState,
+    command_line: &mut CommandLine,
+) {
+    let select = modifiers.shift();
+    match key {
+        VirtualKeyCode::Left => command_line.move_left(select),
+        VirtualKeyCode::Right => command_line.move_right(select),
+        VirtualKeyCode::Home => command_line.move_home(select),
+        VirtualKeyCode::End => command_line.move_end(select),
+        _ => (),
+    }
+}
+
+// function keys change how glyphs are rasterized
+fn change_options(
+    key: VirtualKeyCode,
+    options: RasterOptions,
+) -> Option<RasterOptions> {
+    let mut options = options;
+    match key {
+        VirtualKeyCode::F1 => {
+            options.hinting = match options.hinting {
+                Hinting::None => Hinting::Vertical,
+                Hinting::Vertical => Hinting::Full,
+                Hinting::Full => Hinting::None,
+            }
+        }
+        VirtualKeyCode::F2 => {
+            options.antialiasing = match options.antialiasing {
+                Antialiasing::Grayscale => Antialiasing::Subpixel,
+                Antialiasing::Subpixel => Antialiasing::Grayscale,
+            }
+        }
+        VirtualKeyCode::F3 => options.stem_darkening = !options.stem_darkening,
+        VirtualKeyCode::F4 => options.gamma = (options.gamma - 0.1).max(0.1),
+        VirtualKeyCode::F5 => options.gamma += 0.1,
+        VirtualKeyCode::F6 => {
+            options.contrast = (options.contrast - 0.1).max(0.0)
         }
-        '\n' => {
-            println!("{:?}", command_buffer);
-            command_buffer.clear()
+        VirtualKeyCode::F7 => {
+            options.contrast = (options.contrast + 0.1).min(1.0)
         }
-        _ => command_buffer.push(c),
+        _ => return None,
     }
+    Some(options)
 }
@@ -1,16 +1,22 @@
 use std::cmp::{max, min};
+use std::io::{self, Read, Write};
 use std::ptr;
 
 use font_kit::canvas::{Canvas, Format};
 use gl::types::*;
-use pathfinder_geometry::{
-    rect::RectI,
-    transform2d::Transform2F,
-    vector::{Vector2F, Vector2I},
+use pathfinder_geometry::{rect::RectI, vector::Vector2I};
+
+use super::disk_cache::{
+    invalid_data, read_i32, read_u32, read_u8, write_i32, write_u32, write_u8,
 };
 
-pub const ATLAS_SIZE: i32 = 256;
+// side length atlases start out with before growing
+const ATLAS_INITIAL_SIZE: i32 = 512;
+// upper bound on the side length regardless of what the driver allows, every
+// layer is also kept around on the cpu
+const ATLAS_MAX_SIZE: i32 = 4096;
 
+#[derive(Debug)]
 pub enum AtlasInsertError {
     // the texture atlas is full
     Full,
@@ -19,27 +25,95 @@ pub enum AtlasInsertError {
     GlyphTooLarge,
 }
 
+/// How far an atlas may grow before glyphs have to go into a new one
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasLimits {
+    // side length of every layer
+    pub max_size: i32,
+    pub max_layers: i32,
+}
+
+impl AtlasLimits {
+    /// the largest textures the driver supports. `layered` allows adding
+    /// layers once an atlas can't grow any further, otherwise each atlas is a
+    /// single layer
+    pub fn query(layered: bool) -> Self {
+        let mut max_size = 0;
+        let mut max_layers = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_size);
+            gl::GetIntegerv(gl::MAX_ARRAY_TEXTURE_LAYERS, &mut max_layers);
+        }
+
+        Self {
+            max_size: min(max_size, ATLAS_MAX_SIZE),
+            max_layers: if layered { max_layers } else { 1 },
+        }
+    }
+
+    /// room for exactly one glyph, for ones too large to share an atlas
+    pub fn single(glyph_size: Vector2I) -> Self {
+        Self {
+            max_size: max(glyph_size.x(), glyph_size.y()),
+            max_layers: 1,
+        }
+    }
+}
+
+/// Glyphs packed into the layers of a `GL_TEXTURE_2D_ARRAY`
+///
+/// starts out small and grows up to `AtlasLimits::max_size`, then adds
+/// layers. glyph locations are in pixels so they stay valid as it grows
 #[derive(Debug)]
 pub struct Atlas {
     pub tex_id: GLuint,
-    pub canvas: Canvas,
 
-    // leftmost free x coordinate
-    insert_x: i32,
-    // topmost free y coordinate
-    insert_y: i32,
+    format: Format,
+    // pixel format of the texture, matching the canvases
+    internal_format: GLenum,
+    gl_format: GLenum,
+
+    // empty pixels kept to the right of and below every glyph, so linear
+    // filtering never picks up a neighbour
+    padding: i32,
+
+    // current side length of every layer
+    size: i32,
+    limits: AtlasLimits,
+    layers: Vec<Layer>,
+
+    // texture storage doesn't match the layers anymore and has to be
+    // reallocated on the next upload
+    resized: bool,
+}
+
+#[derive(Debug)]
+struct Layer {
+    canvas: Canvas,
 
-    // portion of atlas not yet uploaded to GPU
+    // rows of glyphs, each as tall as the first glyph placed in it
+    shelves: Vec<Shelf>,
+    // topmost y coordinate not taken by a shelf
+    free_y: i32,
+
+    // portion of layer not yet uploaded to GPU, nothing if the height is 0
     dirty_y: i32,
     dirty_height: i32,
+}
 
-    // height of tallest glyph in current row to use for advancement
-    row_tallest: i32,
+#[derive(Debug)]
+struct Shelf {
+    y: i32,
+    height: i32,
+    // leftmost free x coordinate
+    free_x: i32,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct GlyphTexInfo {
     pub tex_id: GLuint,
+    pub layer: u16,
+    // in pixels, `uv_bot` is the glyph's top row in the texture
     pub uv_left: f32,
     pub uv_bot: f32,
     pub uv_width: f32,
@@ -47,161 +121,331 @@ pub struct GlyphTexInfo {
 }
 
 impl Atlas {
-    pub fn new() -> Self {
+    /// create an atlas holding either coverage masks (`Format::A8`,
+    /// `Format::Rgb24` for subpixel) or premultiplied color glyphs
+    /// (`Format::Rgba32`), with `padding` pixels between glyphs
+    pub fn new(format: Format, padding: i32, limits: AtlasLimits) -> Self {
+        let (internal_format, gl_format) = match format {
+            Format::Rgba32 => (gl::RGBA8, gl::RGBA),
+            Format::Rgb24 => (gl::RGB8, gl::RGB),
+            Format::A8 => (gl::R8, gl::RED),
+        };
+
         let mut tex_id: GLuint = 0;
         unsafe {
-            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
             gl::GenTextures(1, &mut tex_id);
-            gl::BindTexture(gl::TEXTURE_2D, tex_id);
-
-            gl::TexImage2D(
-                gl::TEXTURE_2D,
-                0,
-                gl::RED as i32,
-                ATLAS_SIZE,
-                ATLAS_SIZE,
-                0,
-                gl::RED,
-                gl::UNSIGNED_BYTE,
-                ptr::null(),
-            );
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, tex_id);
+
+            // let the shader treat single channel coverage the same way as
+            // subpixel coverage
+            if format == Format::A8 {
+                let swizzle = [gl::RED as GLint; 4];
+                gl::TexParameteriv(
+                    gl::TEXTURE_2D_ARRAY,
+                    gl::TEXTURE_SWIZZLE_RGBA,
+                    swizzle.as_ptr(),
+                );
+            }
 
             gl::TexParameteri(
-                gl::TEXTURE_2D,
+                gl::TEXTURE_2D_ARRAY,
                 gl::TEXTURE_WRAP_S,
                 gl::CLAMP_TO_EDGE as i32,
             );
             gl::TexParameteri(
-                gl::TEXTURE_2D,
+                gl::TEXTURE_2D_ARRAY,
                 gl::TEXTURE_WRAP_T,
                 gl::CLAMP_TO_EDGE as i32,
             );
             gl::TexParameteri(
-                gl::TEXTURE_2D,
+                gl::TEXTURE_2D_ARRAY,
                 gl::TEXTURE_MIN_FILTER,
                 gl::LINEAR as i32,
             );
             gl::TexParameteri(
-                gl::TEXTURE_2D,
+                gl::TEXTURE_2D_ARRAY,
                 gl::TEXTURE_MAG_FILTER,
                 gl::LINEAR as i32,
             );
 
             // unbind texture
-            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, 0);
         }
 
-        let canvas = Canvas::new(Vector2I::splat(ATLAS_SIZE), Format::A8);
+        let size = min(ATLAS_INITIAL_SIZE, limits.max_size);
 
+        // storage is allocated on the first upload
         Self {
             tex_id,
-            canvas,
-            insert_x: 0,
-            insert_y: 0,
-            dirty_y: 0,
-            dirty_height: 0,
-            row_tallest: 0,
+            format,
+            internal_format,
+            gl_format,
+            padding,
+            size,
+            limits,
+            layers: vec![Layer::new(size, format)],
+            resized: true,
         }
     }
 
+    /// find space for a glyph of `size` and copy its `pixels`, tightly
+    /// packed rows in the atlas' format, there
     pub fn insert(
         &mut self,
-        glyph_bounds: &RectI,
-    ) -> Result<(Transform2F, GlyphTexInfo), AtlasInsertError> {
-        let glyph_width = glyph_bounds.width();
-        let glyph_height = glyph_bounds.height();
-
-        // this should never happen
-        if glyph_width > ATLAS_SIZE || glyph_height > ATLAS_SIZE {
+        size: Vector2I,
+        pixels: &[u8],
+    ) -> Result<GlyphTexInfo, AtlasInsertError> {
+        let padded = size + Vector2I::splat(self.padding);
+
+        if padded.x() > self.limits.max_size
+            || padded.y() > self.limits.max_size
+        {
             return Err(AtlasInsertError::GlyphTooLarge);
         }
 
-        if self.insert_x + glyph_width > ATLAS_SIZE {
-            self.advance_row()?;
+        // nothing to store, e.g. for spaces
+        let (layer, x, y) = if size.x() == 0 || size.y() == 0 {
+            (0, 0, 0)
+        } else {
+            self.allocate(padded)?
+        };
+
+        self.layers[layer].blit(Vector2I::new(x, y), pixels, size);
+
+        Ok(GlyphTexInfo {
+            tex_id: self.tex_id,
+            layer: layer as u16,
+            uv_left: x as f32,
+            uv_bot: y as f32,
+            uv_width: size.x() as f32,
+            uv_height: size.y() as f32,
+        })
+    }
+
+    // find space for a padded glyph in any layer, growing the atlas if
+    // there's none
+    fn allocate(
+        &mut self,
+        size: Vector2I,
+    ) -> Result<(usize, i32, i32), AtlasInsertError> {
+        loop {
+            for (i, layer) in self.layers.iter_mut().enumerate() {
+                if let Some((x, y)) = layer.allocate(size, self.size) {
+                    return Ok((i, x, y));
+                }
+            }
+
+            self.grow()?;
         }
+    }
 
-        if self.insert_y + glyph_height > ATLAS_SIZE {
+    // double the size of every layer, or add one if they're as large as they
+    // can get
+    fn grow(&mut self) -> Result<(), AtlasInsertError> {
+        if self.size < self.limits.max_size {
+            self.size = min(self.size * 2, self.limits.max_size);
+            for layer in &mut self.layers {
+                layer.resize(self.size);
+            }
+        } else if (self.layers.len() as i32) < self.limits.max_layers {
+            self.layers.push(Layer::new(self.size, self.format));
+        } else {
             return Err(AtlasInsertError::Full);
         }
 
-        let x = glyph_bounds.origin_x();
-        let y = glyph_bounds.origin_y();
-        let transform = Transform2F::from_translation(Vector2F::new(
-            (self.insert_x - x) as f32,
-            (self.insert_y - y) as f32,
-        ));
-
-        let uv_left = self.to_uv(self.insert_x);
-        let uv_bot = self.to_uv(self.insert_y);
-        let uv_width = self.to_uv(glyph_width);
-        let uv_height = self.to_uv(glyph_height);
-
-        self.insert_x += glyph_width;
-        self.row_tallest = max(glyph_height, self.row_tallest);
-        self.dirty_y = min(self.dirty_y, self.insert_y);
-        if self.dirty_y + self.dirty_height < self.insert_y + glyph_height {
-            self.dirty_height = self.insert_y + glyph_height - self.dirty_y;
-        }
-
-        Ok((
-            transform,
-            GlyphTexInfo {
-                tex_id: self.tex_id,
-                uv_left,
-                uv_bot,
-                uv_width,
-                uv_height,
-            },
-        ))
-    }
-
-    fn advance_row(&mut self) -> Result<(), AtlasInsertError> {
-        let new_y = self.insert_y + self.row_tallest;
-        if new_y > ATLAS_SIZE {
-            return Err(AtlasInsertError::Full);
+        self.resized = true;
+        Ok(())
+    }
+
+    /// bytes taken up by the texture, not counting the copy on the cpu
+    pub fn memory_usage(&self) -> usize {
+        let layer_size = self.size as usize * self.size as usize;
+        layer_size * self.layers.len() * self.format.bytes_per_pixel() as usize
+    }
+
+    /// whether it's any larger than a new atlas with the same limits
+    pub fn is_grown(&self) -> bool {
+        self.size > min(ATLAS_INITIAL_SIZE, self.limits.max_size)
+            || self.layers.len() > 1
+    }
+
+    /// whether `rect` lies within `layer`
+    pub fn contains(&self, layer: u16, rect: RectI) -> bool {
+        let (origin, size) = (rect.origin(), rect.size());
+        // written so it can't overflow for rects read from a file
+        (layer as usize) < self.layers.len()
+            && origin.x() >= 0
+            && origin.y() >= 0
+            && (0..=self.size - origin.x()).contains(&size.x())
+            && (0..=self.size - origin.y()).contains(&size.y())
+    }
+
+    /// copy of the pixels at `rect` in `layer`, as tightly packed rows
+    pub fn read(&self, layer: u16, rect: RectI) -> Vec<u8> {
+        let canvas = &self.layers[layer as usize].canvas;
+        let bytes_per_pixel = canvas.format.bytes_per_pixel() as usize;
+        let row_len = rect.width() as usize * bytes_per_pixel;
+        let x = rect.origin_x() as usize * bytes_per_pixel;
+
+        let mut pixels = Vec::with_capacity(row_len * rect.height() as usize);
+        for y in rect.min_y()..rect.max_y() {
+            let start = y as usize * canvas.stride + x;
+            pixels.extend_from_slice(&canvas.pixels[start..start + row_len]);
         }
-        self.insert_y = new_y;
-        self.insert_x = 0;
-        self.row_tallest = 0;
+        pixels
+    }
 
+    /// write the layers and their packing to `w`, so the atlas can be
+    /// restored with `read_from` without rasterizing anything
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        write_u8(w, format_tag(self.format))?;
+        write_i32(w, self.padding)?;
+        write_i32(w, self.size)?;
+        write_i32(w, self.limits.max_size)?;
+        write_i32(w, self.limits.max_layers)?;
+
+        write_u32(w, self.layers.len() as u32)?;
+        for layer in &self.layers {
+            write_i32(w, layer.free_y)?;
+            write_u32(w, layer.shelves.len() as u32)?;
+            for shelf in &layer.shelves {
+                write_i32(w, shelf.y)?;
+                write_i32(w, shelf.height)?;
+                write_i32(w, shelf.free_x)?;
+            }
+            w.write_all(&layer.canvas.pixels)?;
+        }
         Ok(())
     }
 
-    // helper to convert pixel values to UV values
-    fn to_uv(&self, num: i32) -> f32 {
-        num as f32 / ATLAS_SIZE as f32
+    /// an atlas written by `write_to`, uploaded in full on the next call to
+    /// `update_texture`
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let format = match read_u8(r)? {
+            0 => Format::A8,
+            1 => Format::Rgb24,
+            2 => Format::Rgba32,
+            _ => return Err(invalid_data("unknown atlas format")),
+        };
+        let padding = read_i32(r)?;
+        let size = read_i32(r)?;
+        let limits = AtlasLimits {
+            max_size: read_i32(r)?,
+            max_layers: read_i32(r)?,
+        };
+        if size <= 0 || size > limits.max_size {
+            return Err(invalid_data("atlas size out of range"));
+        }
+        if !(0..=size).contains(&padding) {
+            return Err(invalid_data("atlas padding out of range"));
+        }
+
+        let layer_count = read_u32(r)?;
+        if layer_count == 0 || layer_count as i32 > limits.max_layers {
+            return Err(invalid_data("atlas layer count out of range"));
+        }
+
+        let mut atlas = Self::new(format, padding, limits);
+        atlas.size = size;
+        atlas.layers.clear();
+        for _ in 0..layer_count {
+            let mut layer = Layer::new(size, format);
+            layer.free_y = read_i32(r)?;
+            if !(0..=size).contains(&layer.free_y) {
+                return Err(invalid_data("atlas shelves out of range"));
+            }
+            for _ in 0..read_u32(r)? {
+                let shelf = Shelf {
+                    y: read_i32(r)?,
+                    height: read_i32(r)?,
+                    free_x: read_i32(r)?,
+                };
+                // shelves are stacked above `free_y`
+                let inside = shelf.y >= 0
+                    && shelf.height >= 0
+                    && shelf.height <= layer.free_y - shelf.y
+                    && (0..=size).contains(&shelf.free_x);
+                if !inside {
+                    return Err(invalid_data("atlas shelf out of range"));
+                }
+                layer.shelves.push(shelf);
+            }
+            r.read_exact(&mut layer.canvas.pixels)?;
+            atlas.layers.push(layer);
+        }
+
+        Ok(atlas)
     }
 
     pub unsafe fn update_texture(&mut self) {
-        if self.dirty_height == 0 {
+        if !self.resized && self.layers.iter().all(|l| l.dirty_height == 0) {
             return;
         }
 
-        let pixels = &self.canvas.pixels;
-        let stride = self.canvas.stride;
-        let start = stride * self.dirty_y as usize;
-        let end = start + stride * self.dirty_height as usize;
-        let pixels_slice = &pixels[start..end];
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.tex_id);
 
-        gl::BindTexture(gl::TEXTURE_2D, self.tex_id);
+        // the old contents are lost along with the storage, so everything
+        // is uploaded again from the canvases
+        if self.resized {
+            gl::TexImage3D(
+                gl::TEXTURE_2D_ARRAY,
+                0,
+                self.internal_format as i32,
+                self.size,
+                self.size,
+                self.layers.len() as i32,
+                0,
+                self.gl_format,
+                gl::UNSIGNED_BYTE,
+                ptr::null(),
+            );
+
+            for layer in &mut self.layers {
+                layer.dirty_y = 0;
+                layer.dirty_height = self.size;
+            }
+            self.resized = false;
+        }
+
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if layer.dirty_height == 0 {
+                continue;
+            }
 
-        gl::TexSubImage2D(
-            gl::TEXTURE_2D,
-            0,
-            0,
-            self.dirty_y,
-            ATLAS_SIZE,
-            self.dirty_height,
-            gl::RED,
-            gl::UNSIGNED_BYTE,
-            pixels_slice.as_ptr() as *const _,
-        );
+            let stride = layer.canvas.stride;
+            let start = stride * layer.dirty_y as usize;
+            let end = start + stride * layer.dirty_height as usize;
+            let pixels_slice = &layer.canvas.pixels[start..end];
+
+            gl::TexSubImage3D(
+                gl::TEXTURE_2D_ARRAY,
+                0,
+                0,
+                layer.dirty_y,
+                i as i32,
+                self.size,
+                layer.dirty_height,
+                1,
+                self.gl_format,
+                gl::UNSIGNED_BYTE,
+                pixels_slice.as_ptr() as *const _,
+            );
+
+            layer.dirty_height = 0;
+        }
 
         // unbind texture
-        // gl::BindTexture(gl::TEXTURE_2D, 0);
+        // gl::BindTexture(gl::TEXTURE_2D_ARRAY, 0);
+    }
+}
 
-        self.dirty_y = ATLAS_SIZE + 1;
-        self.dirty_height = 0;
+// how `format` is stored on disk
+fn format_tag(format: Format) -> u8 {
+    match format {
+        Format::A8 => 0,
+        Format::Rgb24 => 1,
+        Format::Rgba32 => 2,
     }
 }
 
@@ -212,3 +456,201 @@ impl Drop for Atlas {
         }
     }
 }
+
+impl Layer {
+    fn new(size: i32, format: Format) -> Self {
+        Self {
+            canvas: Canvas::new(Vector2I::splat(size), format),
+            shelves: Vec::new(),
+            free_y: 0,
+            dirty_y: 0,
+            dirty_height: 0,
+        }
+    }
+
+    // find space for a padded glyph in a layer of `layer_size`, returning
+    // its top left corner
+    fn allocate(
+        &mut self,
+        size: Vector2I,
+        layer_size: i32,
+    ) -> Option<(i32, i32)> {
+        let (width, height) = (size.x(), size.y());
+
+        // shelf that fits the glyph with the least height left over
+        let best_fit = self
+            .shelves
+            .iter_mut()
+            .filter(|shelf| {
+                shelf.height >= height && shelf.free_x + width <= layer_size
+            })
+            .min_by_key(|shelf| shelf.height);
+
+        let can_open_shelf = self.free_y + height <= layer_size;
+
+        let shelf = match best_fit {
+            // a short glyph in a much taller shelf wastes the space above
+            // it, so only do that once we're out of room for new shelves
+            Some(shelf) if shelf.height - height <= height / 2 => shelf,
+            Some(shelf) if !can_open_shelf => shelf,
+            _ if can_open_shelf => {
+                self.shelves.push(Shelf {
+                    y: self.free_y,
+                    height,
+                    free_x: 0,
+                });
+                self.free_y += height;
+                self.shelves.last_mut().unwrap()
+            }
+            _ => return None,
+        };
+
+        let position = (shelf.free_x, shelf.y);
+        shelf.free_x += width;
+
+        Some(position)
+    }
+
+    // copy the existing contents into a larger canvas
+    fn resize(&mut self, size: i32) {
+        let mut canvas = Canvas::new(Vector2I::splat(size), self.canvas.format);
+
+        let old_stride = self.canvas.stride;
+        let rows = self.canvas.pixels.chunks_exact(old_stride);
+        for (i, row) in rows.enumerate() {
+            let start = i * canvas.stride;
+            canvas.pixels[start..start + old_stride].copy_from_slice(row);
+        }
+
+        self.canvas = canvas;
+    }
+
+    // copy already rasterized pixels in the canvas' format into the layer
+    fn blit(&mut self, dst: Vector2I, pixels: &[u8], size: Vector2I) {
+        let bytes_per_pixel = self.canvas.format.bytes_per_pixel() as usize;
+        let row_len = size.x() as usize * bytes_per_pixel;
+        if row_len == 0 || size.y() == 0 {
+            return;
+        }
+
+        let x = dst.x() as usize * bytes_per_pixel;
+        for (i, row) in pixels.chunks_exact(row_len).enumerate() {
+            let start = (dst.y() as usize + i) * self.canvas.stride + x;
+            self.canvas.pixels[start..start + row_len].copy_from_slice(row);
+        }
+
+        let dirty_end = dst.y() + size.y();
+        if self.dirty_height == 0 {
+            self.dirty_y = dst.y();
+            self.dirty_height = size.y();
+        } else {
+            let dirty_end = max(self.dirty_y + self.dirty_height, dirty_end);
+            self.dirty_y = min(self.dirty_y, dst.y());
+            self.dirty_height = dirty_end - self.dirty_y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYER_SIZE: i32 = 64;
+
+    fn allocate(layer: &mut Layer, width: i32, height: i32) -> (i32, i32) {
+        layer
+            .allocate(Vector2I::new(width, height), LAYER_SIZE)
+            .unwrap()
+    }
+
+    #[test]
+    fn shelves_are_stacked() {
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        assert_eq!(allocate(&mut layer, 10, 20), (0, 0));
+        assert_eq!(allocate(&mut layer, 10, 20), (10, 0));
+        // too short for the first shelf
+        assert_eq!(allocate(&mut layer, 10, 12), (0, 20));
+        assert_eq!(layer.free_y, 32);
+    }
+
+    #[test]
+    fn picks_the_shelf_with_the_least_height_left_over() {
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        allocate(&mut layer, 10, 12);
+        allocate(&mut layer, 10, 14);
+        allocate(&mut layer, 10, 20);
+
+        // fits all of them, but the 12 pixel one best
+        assert_eq!(allocate(&mut layer, 10, 11), (10, 0));
+        assert_eq!(allocate(&mut layer, 10, 13), (10, 12));
+        assert_eq!(allocate(&mut layer, 10, 16), (10, 26));
+        // up to half of the glyph's height may be left over
+        assert_eq!(allocate(&mut layer, 10, 8), (20, 0));
+        assert_eq!(allocate(&mut layer, 10, 7), (0, 46));
+    }
+
+    #[test]
+    fn short_glyphs_get_their_own_shelf_while_there_is_room() {
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        allocate(&mut layer, 10, 20);
+        // 20 - 8 is more than half of 8
+        assert_eq!(allocate(&mut layer, 10, 8), (0, 20));
+        assert_eq!(layer.shelves.len(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_taller_shelves_once_full() {
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        allocate(&mut layer, 10, 40);
+        allocate(&mut layer, 10, 20);
+        assert_eq!(layer.free_y, 60);
+
+        // no room for a new shelf, so the closest one in height is used
+        assert_eq!(allocate(&mut layer, 10, 5), (10, 40));
+        // too wide for what's left of the 20 pixel shelf
+        assert_eq!(allocate(&mut layer, 45, 5), (10, 0));
+        assert_eq!(allocate(&mut layer, 10, 5), (20, 40));
+        assert_eq!(layer.shelves.len(), 2);
+    }
+
+    #[test]
+    fn full_layer() {
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        allocate(&mut layer, LAYER_SIZE, 40);
+        allocate(&mut layer, LAYER_SIZE, 20);
+
+        // both shelves are out of room
+        let size = Vector2I::new(10, 4);
+        assert_eq!(layer.allocate(size, LAYER_SIZE), Some((0, 60)));
+        let size = Vector2I::new(10, 5);
+        assert_eq!(layer.allocate(size, LAYER_SIZE), None);
+        // too tall for the space below them
+        let size = Vector2I::new(10, 8);
+        assert_eq!(layer.allocate(size, LAYER_SIZE), None);
+    }
+
+    #[test]
+    fn padding_is_kept_empty() {
+        // how `Atlas::insert` places glyphs, which needs a gl context
+        let padding = 2;
+        let mut layer = Layer::new(LAYER_SIZE, Format::A8);
+        let size = Vector2I::new(3, 4);
+        let pixels = [255; 12];
+        for _ in 0..2 {
+            let padded = size + Vector2I::splat(padding);
+            let (x, y) = layer.allocate(padded, LAYER_SIZE).unwrap();
+            layer.blit(Vector2I::new(x, y), &pixels, size);
+        }
+
+        let stride = layer.canvas.stride;
+        let row = |y: usize| &layer.canvas.pixels[y * stride..y * stride + 10];
+        for y in 0..4 {
+            assert_eq!(row(y), [255, 255, 255, 0, 0, 255, 255, 255, 0, 0]);
+        }
+        assert_eq!(row(4), [0; 10]);
+        assert_eq!(row(5), [0; 10]);
+        // the shelf includes the padding below the glyphs
+        assert_eq!(layer.free_y, 6);
+        assert_eq!((layer.dirty_y, layer.dirty_height), (0, 4));
+    }
+}
@@ -0,0 +1,508 @@
+use std::ops::Range;
+
+use pathfinder_geometry::{rect::RectI, vector::Vector2I};
+
+use super::glyph_cache::LineMetrics;
+use super::raster::RasterizedGlyph;
+
+// weight of a line leaving the center of a box drawing character
+const N: u8 = 0;
+const L: u8 = 1;
+const H: u8 = 2;
+const D: u8 = 3;
+
+// lines going up, right, down and left for every box drawing character from
+// U+2500, all none for the dashes, arcs and diagonals which are drawn apart
+#[rustfmt::skip]
+const BOX_LINES: [[u8; 4]; 128] = [
+    [N, L, N, L], [N, H, N, H], [L, N, L, N], [H, N, H, N], // ─━│┃
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ┄┅┆┇
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ┈┉┊┋
+    [N, L, L, N], [N, H, L, N], [N, L, H, N], [N, H, H, N], // ┌┍┎┏
+    [N, N, L, L], [N, N, L, H], [N, N, H, L], [N, N, H, H], // ┐┑┒┓
+    [L, L, N, N], [L, H, N, N], [H, L, N, N], [H, H, N, N], // └┕┖┗
+    [L, N, N, L], [L, N, N, H], [H, N, N, L], [H, N, N, H], // ┘┙┚┛
+    [L, L, L, N], [L, H, L, N], [H, L, L, N], [L, L, H, N], // ├┝┞┟
+    [H, L, H, N], [H, H, L, N], [L, H, H, N], [H, H, H, N], // ┠┡┢┣
+    [L, N, L, L], [L, N, L, H], [H, N, L, L], [L, N, H, L], // ┤┥┦┧
+    [H, N, H, L], [H, N, L, H], [L, N, H, H], [H, N, H, H], // ┨┩┪┫
+    [N, L, L, L], [N, L, L, H], [N, H, L, L], [N, H, L, H], // ┬┭┮┯
+    [N, L, H, L], [N, L, H, H], [N, H, H, L], [N, H, H, H], // ┰┱┲┳
+    [L, L, N, L], [L, L, N, H], [L, H, N, L], [L, H, N, H], // ┴┵┶┷
+    [H, L, N, L], [H, L, N, H], [H, H, N, L], [H, H, N, H], // ┸┹┺┻
+    [L, L, L, L], [L, L, L, H], [L, H, L, L], [L, H, L, H], // ┼┽┾┿
+    [H, L, L, L], [L, L, H, L], [H, L, H, L], [H, L, L, H], // ╀╁╂╃
+    [H, H, L, L], [L, L, H, H], [L, H, H, L], [H, H, L, H], // ╄╅╆╇
+    [L, H, H, H], [H, L, H, H], [H, H, H, L], [H, H, H, H], // ╈╉╊╋
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╌╍╎╏
+    [N, D, N, D], [D, N, D, N], [N, D, L, N], [N, L, D, N], // ═║╒╓
+    [N, D, D, N], [N, N, L, D], [N, N, D, L], [N, N, D, D], // ╔╕╖╗
+    [L, D, N, N], [D, L, N, N], [D, D, N, N], [L, N, N, D], // ╘╙╚╛
+    [D, N, N, L], [D, N, N, D], [L, D, L, N], [D, L, D, N], // ╜╝╞╟
+    [D, D, D, N], [L, N, L, D], [D, N, D, L], [D, N, D, D], // ╠╡╢╣
+    [N, D, L, D], [N, L, D, L], [N, D, D, D], [L, D, N, D], // ╤╥╦╧
+    [D, L, N, L], [D, D, N, D], [L, D, L, D], [D, L, D, L], // ╨╩╪╫
+    [D, D, D, D], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╬╭╮╯
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╰╱╲╳
+    [N, N, N, L], [L, N, N, N], [N, L, N, N], [N, N, L, N], // ╴╵╶╷
+    [N, N, N, H], [H, N, N, N], [N, H, N, N], [N, N, H, N], // ╸╹╺╻
+    [N, H, N, L], [L, N, H, N], [N, L, N, H], [H, N, L, N], // ╼╽╾╿
+];
+
+// filled quarters of the quadrant block elements from U+2596, as bits for
+// the upper left, upper right, lower left and lower right
+const QUADRANTS: [u8; 10] = [4, 8, 1, 13, 9, 7, 11, 2, 6, 14];
+
+// braille dots in the order of their bits, as column and row. the bottom row
+// was added after the other three so its dots come last
+const BRAILLE_DOTS: [(u8, u8); 8] = [
+    (0, 0),
+    (0, 1),
+    (0, 2),
+    (1, 0),
+    (1, 1),
+    (1, 2),
+    (0, 3),
+    (1, 3),
+];
+
+/// whether `c` is drawn by `rasterize` rather than taken from a font: box
+/// drawing, block elements and braille patterns
+pub fn is_builtin(c: char) -> bool {
+    matches!(c, '\u{2500}'..='\u{259f}' | '\u{2800}'..='\u{28ff}')
+}
+
+/// draw `c` so it fills a grid cell of the monospace font with `metrics`
+/// exactly, and lines up with the characters in the cells around it
+///
+/// the result is grayscale coverage, placed like any other glyph relative to
+/// a pen on the baseline
+pub fn rasterize(c: char, metrics: &LineMetrics) -> Option<RasterizedGlyph> {
+    let width = metrics.cell_width.round().max(1.0) as i32;
+    let height = metrics.line_height.round().max(1.0) as i32;
+    // lines are as thick as an underline, heavy ones twice that
+    let thickness = metrics.underline_thickness.round().max(1.0);
+
+    let mut canvas = Canvas::new(width, height);
+    let code = c as u32;
+    match code {
+        0x2500..=0x257f => box_drawing(&mut canvas, code - 0x2500, thickness),
+        0x2580..=0x259f => block(&mut canvas, code - 0x2580),
+        0x2800..=0x28ff => braille(&mut canvas, code - 0x2800),
+        _ => return None,
+    }
+
+    // the bottom of the cell is the descent below the baseline
+    let top = height + metrics.descent.round() as i32;
+    Some(RasterizedGlyph {
+        bounds: RectI::new(
+            Vector2I::new(0, -top),
+            Vector2I::new(width, height),
+        ),
+        pixels: canvas.pixels,
+    })
+}
+
+fn box_drawing(canvas: &mut Canvas, index: u32, thickness: f32) {
+    let (width, height) = canvas.size();
+    // the middle of the cell, moved so light lines cover whole pixels
+    let center_x = snap(width / 2.0, thickness);
+    let center_y = snap(height / 2.0, thickness);
+    let center = (
+        (center_x.start + center_x.end) / 2.0,
+        (center_y.start + center_y.end) / 2.0,
+    );
+    let weight = |heavy: bool| if heavy { H } else { L };
+
+    match index {
+        // triple, quadruple and double dashes, each light and heavy across
+        // then down
+        0x04..=0x0b | 0x4c..=0x4f => {
+            let (count, i) = match index {
+                0x04..=0x07 => (3, index - 0x04),
+                0x08..=0x0b => (4, index - 0x08),
+                _ => (2, index - 0x4c),
+            };
+            let line = line_width(weight(i % 2 == 1), thickness);
+            dashes(canvas, count, line, i >= 2, center);
+        }
+        0x6d => arc(canvas, (1.0, 1.0), thickness, center),
+        0x6e => arc(canvas, (-1.0, 1.0), thickness, center),
+        0x6f => arc(canvas, (-1.0, -1.0), thickness, center),
+        0x70 => arc(canvas, (1.0, -1.0), thickness, center),
+        0x71 => diagonal(canvas, true, thickness),
+        0x72 => diagonal(canvas, false, thickness),
+        0x73 => {
+            diagonal(canvas, true, thickness);
+            diagonal(canvas, false, thickness);
+        }
+        _ => lines(canvas, BOX_LINES[index as usize], thickness, center),
+    }
+}
+
+// how wide a line of `weight` is across, double lines being two light ones
+// with a light line's gap between them
+fn line_width(weight: u8, thickness: f32) -> f32 {
+    match weight {
+        N => 0.0,
+        L => thickness,
+        H => 2.0 * thickness,
+        _ => 3.0 * thickness,
+    }
+}
+
+// lines from the center out to the middle of each edge. single lines cover
+// the junction, while either side of a double line stops at a line next to
+// it, leaving corners and crossings open
+fn lines(
+    canvas: &mut Canvas,
+    arms: [u8; 4],
+    thickness: f32,
+    (center_x, center_y): (f32, f32),
+) {
+    let (width, height) = canvas.size();
+    let extent = |weight: u8| line_width(weight, thickness) / 2.0;
+
+    for (i, &weight) in arms.iter().enumerate() {
+        if weight == N {
+            continue;
+        }
+
+        // the lines to the left and right of this one when it's vertical,
+        // above and below when it's horizontal
+        let (before, after) = if i % 2 == 0 {
+            (arms[3], arms[1])
+        } else {
+            (arms[0], arms[2])
+        };
+        // each band as its offset from the center across the line, its
+        // width and how far from the center it starts, negative past it
+        let bands = if weight == D {
+            let start = |side: u8, other: u8| match (side, other) {
+                (D, _) => thickness / 2.0,
+                (N, N) => 0.0,
+                (N, other) => -extent(other),
+                (side, _) => -extent(side),
+            };
+            vec![
+                (-thickness, thickness, start(before, after)),
+                (thickness, thickness, start(after, before)),
+            ]
+        } else {
+            let start = -(extent(before).max(extent(after)));
+            vec![(0.0, line_width(weight, thickness), start)]
+        };
+
+        for (offset, line, start) in bands {
+            let across_x = snap(center_x + offset, line);
+            let across_y = snap(center_y + offset, line);
+            match i {
+                0 => canvas.fill(across_x, 0.0..center_y - start, 255),
+                1 => canvas.fill(center_x + start..width, across_y, 255),
+                2 => canvas.fill(across_x, center_y + start..height, 255),
+                _ => canvas.fill(0.0..center_x - start, across_y, 255),
+            }
+        }
+    }
+}
+
+// `count` dashes `line` wide through the center, with half a gap at either
+// end so they stay evenly spaced from one cell to the next
+fn dashes(
+    canvas: &mut Canvas,
+    count: u32,
+    line: f32,
+    vertical: bool,
+    (center_x, center_y): (f32, f32),
+) {
+    let (width, height) = canvas.size();
+    let length = if vertical { height } else { width };
+    let step = length / count as f32;
+    for i in 0..count {
+        let start = i as f32 * step + step / 4.0;
+        let along = start..start + step / 2.0;
+        if vertical {
+            canvas.fill(snap(center_x, line), along, 255);
+        } else {
+            canvas.fill(along, snap(center_y, line), 255);
+        }
+    }
+}
+
+// a light line from the middle of one edge to the middle of another, rounded
+// off with a quarter circle. `towards` points at the edges it joins
+fn arc(
+    canvas: &mut Canvas,
+    towards: (f32, f32),
+    thickness: f32,
+    (center_x, center_y): (f32, f32),
+) {
+    let (width, height) = canvas.size();
+    let (dx, dy) = towards;
+    let radius = center_x
+        .min(width - center_x)
+        .min(center_y)
+        .min(height - center_y);
+    let circle_x = center_x + dx * radius;
+    let circle_y = center_y + dy * radius;
+
+    // the quarter of the circle facing the center
+    canvas.shade(|x, y| {
+        if (x - circle_x) * dx > 0.0 || (y - circle_y) * dy > 0.0 {
+            return 0.0;
+        }
+        let distance = ((x - circle_x).hypot(y - circle_y) - radius).abs();
+        thickness / 2.0 + 0.5 - distance
+    });
+
+    // then straight on to the edges when the cell isn't square
+    let across = if dx > 0.0 {
+        circle_x..width
+    } else {
+        0.0..circle_x
+    };
+    let down = if dy > 0.0 {
+        circle_y..height
+    } else {
+        0.0..circle_y
+    };
+    canvas.fill(across, snap(center_y, thickness), 255);
+    canvas.fill(snap(center_x, thickness), down, 255);
+}
+
+// a light line through opposite corners, going up to the right if `rising`.
+// it carries on through the corners into the diagonal neighbours
+fn diagonal(canvas: &mut Canvas, rising: bool, thickness: f32) {
+    let (width, height) = canvas.size();
+    let length = width.hypot(height);
+    canvas.shade(|x, y| {
+        let distance = if rising {
+            (height * x + width * y - width * height).abs()
+        } else {
+            (height * x - width * y).abs()
+        } / length;
+        thickness / 2.0 + 0.5 - distance
+    });
+}
+
+fn block(canvas: &mut Canvas, index: u32) {
+    let (width, height) = canvas.size();
+    let eighths = |length: f32, n: u32| length * n as f32 / 8.0;
+
+    match index {
+        // upper half
+        0x00 => canvas.fill(0.0..width, 0.0..height / 2.0, 255),
+        // lower eighths up to full
+        0x01..=0x08 => {
+            let top = height - eighths(height, index);
+            canvas.fill(0.0..width, top..height, 255);
+        }
+        // left eighths from seven down to one
+        0x09..=0x0f => {
+            let right = eighths(width, 0x10 - index);
+            canvas.fill(0.0..right, 0.0..height, 255);
+        }
+        // right half
+        0x10 => canvas.fill(width / 2.0..width, 0.0..height, 255),
+        // light, medium and dark shade, as flat coverage rather than a
+        // dither so neighbouring cells blend together
+        0x11..=0x13 => {
+            let coverage = 64 * (index - 0x10) as u8;
+            canvas.fill(0.0..width, 0.0..height, coverage);
+        }
+        // upper and right eighth
+        0x14 => canvas.fill(0.0..width, 0.0..eighths(height, 1), 255),
+        0x15 => canvas.fill(width - eighths(width, 1)..width, 0.0..height, 255),
+        _ => {
+            let quadrants = QUADRANTS[(index - 0x16) as usize];
+            let (half_x, half_y) = (width / 2.0, height / 2.0);
+            let areas = [
+                (0.0..half_x, 0.0..half_y),
+                (half_x..width, 0.0..half_y),
+                (0.0..half_x, half_y..height),
+                (half_x..width, half_y..height),
+            ];
+            for (bit, (x, y)) in areas.into_iter().enumerate() {
+                if quadrants & (1 << bit) != 0 {
+                    canvas.fill(x, y, 255);
+                }
+            }
+        }
+    }
+}
+
+// round dots on a grid of two columns and four rows
+fn braille(canvas: &mut Canvas, index: u32) {
+    let (width, height) = canvas.size();
+    let radius = (width / 4.0).min(height / 8.0) * 0.6;
+
+    for (bit, &(col, row)) in BRAILLE_DOTS.iter().enumerate() {
+        if index & (1 << bit) == 0 {
+            continue;
+        }
+        let dot_x = (2 * col + 1) as f32 * width / 4.0;
+        let dot_y = (2 * row + 1) as f32 * height / 8.0;
+        canvas.shade(|x, y| radius + 0.5 - (x - dot_x).hypot(y - dot_y));
+    }
+}
+
+// `width` pixels centered on `center` as close as they get to whole pixels
+fn snap(center: f32, width: f32) -> Range<f32> {
+    let start = (center - width / 2.0).round();
+    start..start + width
+}
+
+// coverage of a cell, with y pointing down. overlapping shapes keep whichever
+// covers a pixel more
+struct Canvas {
+    width: i32,
+    height: i32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+        }
+    }
+
+    fn size(&self) -> (f32, f32) {
+        (self.width as f32, self.height as f32)
+    }
+
+    // fill a rectangle, with its edges rounded to whole pixels
+    fn fill(&mut self, x: Range<f32>, y: Range<f32>, coverage: u8) {
+        let pixel = |edge: f32, max: i32| (edge.round() as i32).clamp(0, max);
+        for row in pixel(y.start, self.height)..pixel(y.end, self.height) {
+            for col in pixel(x.start, self.width)..pixel(x.end, self.width) {
+                self.set(col, row, coverage);
+            }
+        }
+    }
+
+    // coverage from 0 to 1 for every pixel, given the pixel's center
+    fn shade(&mut self, coverage: impl Fn(f32, f32) -> f32) {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let c = coverage(col as f32 + 0.5, row as f32 + 0.5);
+                self.set(col, row, (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            }
+        }
+    }
+
+    fn set(&mut self, x: i32, y: i32, coverage: u8) {
+        let pixel = &mut self.pixels[(y * self.width + x) as usize];
+        *pixel = (*pixel).max(coverage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRICS: LineMetrics = LineMetrics {
+        ascent: 15.0,
+        descent: -5.0,
+        line_height: 20.0,
+        cell_width: 10.0,
+        underline_position: -2.0,
+        underline_thickness: 2.0,
+        strikeout_position: 5.0,
+    };
+
+    // coverage of the glyph for `c`, row by row from the top
+    fn rows(c: char) -> Vec<Vec<u8>> {
+        let glyph = rasterize(c, &METRICS).unwrap();
+        let width = glyph.bounds.width() as usize;
+        glyph
+            .pixels
+            .chunks_exact(width)
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    #[test]
+    fn fills_the_cell() {
+        let glyph = rasterize('\u{2500}', &METRICS).unwrap();
+        // the bottom is the descent below the baseline
+        assert_eq!(
+            glyph.bounds,
+            RectI::new(Vector2I::new(0, -15), Vector2I::new(10, 20))
+        );
+        assert_eq!(glyph.pixels.len(), 200);
+    }
+
+    #[test]
+    fn lines_go_through_the_center() {
+        // ─ and │
+        let across = rows('\u{2500}');
+        let down = rows('\u{2502}');
+        for (y, row) in across.iter().enumerate() {
+            let covered = row.iter().all(|&coverage| coverage == 255);
+            assert_eq!(covered, (9..11).contains(&y), "row {}", y);
+            assert!(covered || row.iter().all(|&coverage| coverage == 0));
+        }
+        for row in &down {
+            assert_eq!(row[..4], [0; 4]);
+            assert_eq!(row[4..6], [255; 2]);
+            assert_eq!(row[6..], [0; 4]);
+        }
+    }
+
+    #[test]
+    fn blocks() {
+        // █ and ▀
+        assert!(rows('\u{2588}')
+            .concat()
+            .iter()
+            .all(|&coverage| coverage == 255));
+        let upper_half = rows('\u{2580}');
+        assert!(upper_half[..10]
+            .concat()
+            .iter()
+            .all(|&coverage| coverage == 255));
+        assert!(upper_half[10..]
+            .concat()
+            .iter()
+            .all(|&coverage| coverage == 0));
+    }
+
+    #[test]
+    fn braille_dots() {
+        // ⠁, the dot in the top left
+        let dot = rows('\u{2801}');
+        assert_eq!(dot[2][2], 255);
+        assert_eq!(dot[2][7], 0);
+        assert_eq!(dot[7][2], 0);
+        assert_eq!(dot[17][2], 0);
+        // nothing but the dot is covered
+        let covered = dot
+            .concat()
+            .iter()
+            .filter(|&&coverage| coverage > 0)
+            .count();
+        assert!(covered < 20, "{} pixels covered", covered);
+        assert!(rows('\u{2800}')
+            .concat()
+            .iter()
+            .all(|&coverage| coverage == 0));
+    }
+
+    #[test]
+    fn builtin_ranges() {
+        for c in [
+            '\u{2500}', '\u{257f}', '\u{2580}', '\u{259f}', '\u{2800}',
+            '\u{28ff}',
+        ] {
+            assert!(is_builtin(c), "{:?}", c);
+            assert!(rasterize(c, &METRICS).is_some(), "{:?}", c);
+        }
+        for c in ['\u{24ff}', '\u{25a0}', '\u{27ff}', '\u{2900}', 'a'] {
+            assert!(!is_builtin(c), "{:?}", c);
+            assert!(rasterize(c, &METRICS).is_none(), "{:?}", c);
+        }
+    }
+}
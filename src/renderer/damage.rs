@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+// frames of damage kept around, back buffers older than this are redrawn in
+// full
+const MAX_BUFFER_AGE: usize = 4;
+
+/// A rectangle of the window in physical pixels, from its top left corner
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl DamageRect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+
+    /// the smallest rectangle covering both
+    pub fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    // the part inside `bounds`, empty if there's none
+    fn clamp(self, bounds: Self) -> Self {
+        let x = self.x.max(bounds.x);
+        let y = self.y.max(bounds.y);
+        let right = (self.x + self.width).min(bounds.x + bounds.width);
+        let bottom = (self.y + self.height).min(bounds.y + bounds.height);
+        Self::new(x, y, right - x, bottom - y)
+    }
+}
+
+/// Parts of the window that changed over the last few frames
+///
+/// a back buffer still holding the frame from a few swaps ago only needs
+/// what changed since then drawn over it, everything else is left as it is
+#[derive(Debug)]
+pub struct DamageTracker {
+    window: DamageRect,
+    // the frame about to be drawn first, then the ones before it
+    frames: VecDeque<Vec<DamageRect>>,
+}
+
+impl DamageTracker {
+    /// the whole window starts out damaged
+    pub fn new(width: i32, height: i32) -> Self {
+        let mut tracker = Self {
+            window: DamageRect::new(0, 0, width, height),
+            frames: VecDeque::from([Vec::new()]),
+        };
+        tracker.damage_all();
+        tracker
+    }
+
+    /// damages the whole window, which may have lost its contents
+    pub fn resize(&mut self, width: i32, height: i32) {
+        self.window = DamageRect::new(0, 0, width, height);
+        self.damage_all();
+    }
+
+    pub fn damage(&mut self, rect: DamageRect) {
+        let rect = rect.clamp(self.window);
+        if !rect.is_empty() {
+            self.frames[0].push(rect);
+        }
+    }
+
+    pub fn damage_all(&mut self) {
+        self.frames[0] = vec![self.window];
+    }
+
+    /// what changed in the frame about to be drawn, empty if nothing did
+    pub fn frame(&self) -> &[DamageRect] {
+        &self.frames[0]
+    }
+
+    /// the area to draw over a back buffer holding the frame from
+    /// `buffer_age` swaps ago, which is the whole window for an age of 0
+    /// since that means its contents are unknown
+    pub fn redraw_area(&self, buffer_age: u32) -> Option<DamageRect> {
+        let age = buffer_age as usize;
+        if age == 0 || age > self.frames.len() {
+            return Some(self.window);
+        }
+
+        self.frames
+            .iter()
+            .take(age)
+            .flatten()
+            .copied()
+            .reduce(DamageRect::union)
+    }
+
+    /// call once the frame has been swapped to the screen
+    pub fn swapped(&mut self) {
+        self.frames.push_front(Vec::new());
+        self.frames.truncate(MAX_BUFFER_AGE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: DamageRect = DamageRect::new(0, 0, 100, 50);
+
+    // a tracker with nothing left to draw
+    fn drawn() -> DamageTracker {
+        let mut tracker = DamageTracker::new(WINDOW.width, WINDOW.height);
+        for _ in 0..MAX_BUFFER_AGE {
+            tracker.swapped();
+        }
+        tracker
+    }
+
+    #[test]
+    fn starts_out_damaged() {
+        let tracker = DamageTracker::new(WINDOW.width, WINDOW.height);
+        assert_eq!(tracker.frame(), [WINDOW]);
+        assert_eq!(tracker.redraw_area(1), Some(WINDOW));
+    }
+
+    #[test]
+    fn damage_is_clamped_to_the_window() {
+        let mut tracker = drawn();
+        tracker.damage(DamageRect::new(90, -10, 20, 20));
+        tracker.damage(DamageRect::new(100, 0, 10, 10));
+        assert_eq!(tracker.frame(), [DamageRect::new(90, 0, 10, 10)]);
+    }
+
+    #[test]
+    fn unknown_buffer_contents_are_redrawn_in_full() {
+        let mut tracker = drawn();
+        tracker.damage(DamageRect::new(10, 10, 5, 5));
+        assert_eq!(tracker.redraw_area(0), Some(WINDOW));
+        // older than any frame that's kept
+        let age = MAX_BUFFER_AGE as u32 + 1;
+        assert_eq!(tracker.redraw_area(age), Some(WINDOW));
+    }
+
+    #[test]
+    fn nothing_to_redraw() {
+        let tracker = drawn();
+        assert!(tracker.frame().is_empty());
+        assert_eq!(tracker.redraw_area(1), None);
+        assert_eq!(tracker.redraw_area(MAX_BUFFER_AGE as u32), None);
+    }
+
+    #[test]
+    fn redraw_area_covers_the_frames_since_the_buffer_was_drawn() {
+        let mut tracker = drawn();
+        tracker.damage(DamageRect::new(0, 0, 10, 10));
+        tracker.swapped();
+        tracker.damage(DamageRect::new(50, 20, 10, 10));
+        tracker.swapped();
+        tracker.damage(DamageRect::new(20, 5, 10, 10));
+        tracker.damage(DamageRect::new(30, 10, 10, 10));
+
+        assert_eq!(
+            tracker.redraw_area(1),
+            Some(DamageRect::new(20, 5, 20, 15))
+        );
+        assert_eq!(
+            tracker.redraw_area(2),
+            Some(DamageRect::new(20, 5, 40, 25))
+        );
+        assert_eq!(tracker.redraw_area(3), Some(DamageRect::new(0, 0, 60, 30)));
+        // the frame before those didn't change anything
+        assert_eq!(tracker.redraw_area(4), Some(DamageRect::new(0, 0, 60, 30)));
+    }
+
+    #[test]
+    fn swapped_forgets_old_frames() {
+        let mut tracker = drawn();
+        tracker.damage(DamageRect::new(0, 0, 10, 10));
+        for _ in 0..MAX_BUFFER_AGE - 1 {
+            tracker.swapped();
+        }
+        let age = MAX_BUFFER_AGE as u32;
+        assert_eq!(
+            tracker.redraw_area(age),
+            Some(DamageRect::new(0, 0, 10, 10))
+        );
+
+        tracker.swapped();
+        assert_eq!(tracker.redraw_area(age), None);
+        assert_eq!(tracker.redraw_area(age + 1), Some(WINDOW));
+    }
+
+    #[test]
+    fn resize_damages_everything() {
+        let mut tracker = drawn();
+        tracker.resize(200, 100);
+        assert_eq!(tracker.frame(), [DamageRect::new(0, 0, 200, 100)]);
+    }
+}
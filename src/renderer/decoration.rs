@@ -0,0 +1,136 @@
+use bitflags::bitflags;
+
+use super::glyph_cache::LineMetrics;
+
+bitflags! {
+    /// Lines drawn along text, placed by the metrics of its font
+    #[derive(Default)]
+    pub struct Decoration: u8 {
+        const UNDERLINE        = 0b0000_0001;
+        const DOUBLE_UNDERLINE = 0b0000_0010;
+        // a wavy underline, for spelling mistakes and errors
+        const UNDERCURL        = 0b0000_0100;
+        const STRIKETHROUGH    = 0b0000_1000;
+        const OVERLINE         = 0b0001_0000;
+    }
+}
+
+/// One line of a decoration, in whole pixels
+#[derive(Copy, Clone, Debug)]
+pub(super) struct DecorationLine {
+    // top edge, up from the baseline
+    pub top: i16,
+    pub height: i16,
+    // the quad holds a wave `thickness` thick rather than being filled
+    pub curly: bool,
+    pub thickness: f32,
+}
+
+impl Decoration {
+    /// the lines making up every decoration in the set, for text with
+    /// `metrics`
+    pub(super) fn lines(self, metrics: &LineMetrics) -> Vec<DecorationLine> {
+        let thickness = metrics.underline_thickness.round().max(1.0);
+        let ascent = metrics.ascent.round();
+        let descent = metrics.descent.round();
+        // a line `height` pixels tall centered on `y`, moved inside the line
+        // box if it sticks out. small fonts have little room below the
+        // underline
+        let line = |y: f32, height: f32, curly: bool| DecorationLine {
+            top: (y + height / 2.0).round().min(ascent).max(descent + height)
+                as i16,
+            height: height as i16,
+            curly,
+            thickness,
+        };
+
+        let underline = metrics.underline_position;
+        let mut lines = Vec::new();
+        if self.contains(Decoration::UNDERLINE) {
+            lines.push(line(underline, thickness, false));
+        }
+        if self.contains(Decoration::DOUBLE_UNDERLINE) {
+            // the second line goes below, a line's thickness apart. both
+            // move up if that's past the descent
+            let lower =
+                (underline - 2.0 * thickness).max(descent + thickness / 2.0);
+            lines.push(line(lower + 2.0 * thickness, thickness, false));
+            lines.push(line(lower, thickness, false));
+        }
+        if self.contains(Decoration::UNDERCURL) {
+            // peaks a line's thickness above and below the underline
+            lines.push(line(underline, 3.0 * thickness, true));
+        }
+        if self.contains(Decoration::STRIKETHROUGH) {
+            lines.push(line(metrics.strikeout_position, thickness, false));
+        }
+        if self.contains(Decoration::OVERLINE) {
+            // right below the top of the ascent
+            lines.push(line(
+                metrics.ascent - thickness / 2.0,
+                thickness,
+                false,
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(size: f32) -> LineMetrics {
+        LineMetrics {
+            ascent: size * 0.93,
+            descent: -size * 0.24,
+            line_height: size * 1.17,
+            cell_width: size * 0.6,
+            underline_position: -size * 0.1,
+            underline_thickness: size * 0.05,
+            strikeout_position: size * 0.26,
+        }
+    }
+
+    #[test]
+    fn double_underlines_are_a_thickness_apart() {
+        for size in [8.0, 12.0, 16.0, 32.0, 72.0] {
+            let lines = Decoration::DOUBLE_UNDERLINE.lines(&metrics(size));
+            let [upper, lower] = lines[..] else {
+                panic!("{} lines at {}", lines.len(), size);
+            };
+            assert_eq!(upper.height, lower.height);
+            assert_eq!(upper.top - upper.height - lower.top, upper.height);
+        }
+    }
+
+    #[test]
+    fn lines_stay_inside_the_line_box() {
+        for size in [6.0, 8.0, 12.0, 16.0, 32.0, 72.0] {
+            let metrics = metrics(size);
+            for line in Decoration::all().lines(&metrics) {
+                assert!(line.height >= 1);
+                assert!(
+                    line.top as f32 <= metrics.ascent.round()
+                        && (line.top - line.height) as f32
+                            >= metrics.descent.round(),
+                    "{:?} at {}",
+                    line,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lines_are_in_order() {
+        let metrics = metrics(16.0);
+        let top = |decoration: Decoration| decoration.lines(&metrics)[0].top;
+        assert!(top(Decoration::OVERLINE) > top(Decoration::STRIKETHROUGH));
+        assert!(top(Decoration::STRIKETHROUGH) > top(Decoration::UNDERLINE));
+        assert_eq!(
+            top(Decoration::UNDERLINE),
+            top(Decoration::DOUBLE_UNDERLINE)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+// identifies glyph cache files, followed by `VERSION`
+const MAGIC: &[u8; 4] = b"RTGC";
+// bumped whenever the layout of the file changes
+const VERSION: u32 = 2;
+
+// the user's cache directory, following the XDG base directory spec
+fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cache")))?;
+    Some(base.join("raytrace").join("glyphs"))
+}
+
+fn cache_path(key: u64) -> Option<PathBuf> {
+    Some(cache_dir()?.join(format!("{:016x}.bin", key)))
+}
+
+/// the cache file for `key`, positioned after its header. `None` if there is
+/// none or it was written by another version or for another key
+pub fn open(key: u64) -> Option<impl Read> {
+    let file = File::open(cache_path(key)?).ok()?;
+    let mut r = BufReader::new(file);
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic).ok()?;
+    let matches = &magic == MAGIC
+        && read_u32(&mut r).ok()? == VERSION
+        && read_u64(&mut r).ok()? == key;
+    matches.then_some(r)
+}
+
+/// replace the cache file for `key` with whatever `write` writes after the
+/// header. the file only shows up once it's complete, so a crash midway
+/// leaves the old one in place
+pub fn save(
+    key: u64,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let path = cache_path(key).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "no cache directory")
+    })?;
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp_path)?);
+    w.write_all(MAGIC)?;
+    write_u32(&mut w, VERSION)?;
+    write_u64(&mut w, key)?;
+    write(&mut w)?;
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// everything is little endian
+
+pub fn write_u8(w: &mut impl Write, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+pub fn write_u16(w: &mut impl Write, value: u16) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_i16(w: &mut impl Write, value: i16) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_i32(w: &mut impl Write, value: i32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32(w: &mut impl Write, value: f32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    r.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+pub fn read_i16(r: &mut impl Read) -> io::Result<i16> {
+    let mut bytes = [0; 2];
+    r.read_exact(&mut bytes)?;
+    Ok(i16::from_le_bytes(bytes))
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
@@ -1,22 +1,37 @@
+use std::cell::OnceCell;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::hash::BuildHasherDefault;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Read};
+use std::mem;
+use std::sync::Arc;
 use std::vec::Vec;
 
 use fnv::FnvHasher;
 use font_kit::{
-    canvas::RasterizationOptions,
+    canvas::Format,
     error::{FontLoadingError, GlyphLoadingError, SelectionError},
     family_name::FamilyName,
     font::Font,
-    hinting::HintingOptions,
-    properties::Properties,
-    source::SystemSource,
+    handle::Handle,
+    metrics::Metrics,
+    properties::{Properties, Style, Weight},
+    source::{Source, SystemSource},
 };
-use pathfinder_geometry::{rect::RectI, transform2d::Transform2F};
+use gl::types::*;
+use pathfinder_geometry::{rect::RectI, vector::Vector2I};
 
-use super::atlas::{Atlas, AtlasInsertError, GlyphTexInfo};
+use super::atlas::{Atlas, AtlasInsertError, AtlasLimits, GlyphTexInfo};
+use super::builtin;
+use super::disk_cache::{
+    self, invalid_data, read_f32, read_i16, read_u16, read_u32, read_u8,
+    write_f32, write_i16, write_u16, write_u32, write_u8,
+};
+use super::raster::{self, RasterizedGlyph};
+use super::sdf;
+use super::shaper::{Direction, ShapedGlyph, Shaper};
 
 // #[derive(Debug)]
 // pub enum GlyphCacheError {
@@ -39,6 +54,8 @@ use super::atlas::{Atlas, AtlasInsertError, GlyphTexInfo};
 pub enum GlyphCacheError {
     SelectionError(SelectionError),
     FontLoadingError(FontLoadingError),
+    // the font's raw data is needed for shaping
+    NoFontData,
 }
 
 impl Error for GlyphCacheError {}
@@ -48,6 +65,9 @@ impl fmt::Display for GlyphCacheError {
         match &self {
             GlyphCacheError::SelectionError(e) => e.fmt(f),
             GlyphCacheError::FontLoadingError(e) => e.fmt(f),
+            GlyphCacheError::NoFontData => {
+                write!(f, "Font data is not available for shaping")
+            }
         }
     }
 }
@@ -64,104 +84,1215 @@ impl From<FontLoadingError> for GlyphCacheError {
     }
 }
 
+/// A rasterized glyph and its location in one of the atlases
+#[derive(Copy, Clone, Debug)]
+pub struct Glyph {
+    pub tex_id: GLuint,
+    pub layer: u16,
+
+    // offset of the bitmap from the pen position, with y pointing up
+    pub left: i16,
+    pub top: i16,
+
+    // bitmap size in pixels
+    pub width: i16,
+    pub height: i16,
+
+    // in pixels
+    pub uv_left: f32,
+    pub uv_bot: f32,
+    pub uv_width: f32,
+    pub uv_height: f32,
+
+    // stored as premultiplied RGBA rather than a coverage mask
+    pub colored: bool,
+}
+
+impl Glyph {
+    fn new(bounds: &RectI, tex_info: &GlyphTexInfo, colored: bool) -> Self {
+        Self {
+            tex_id: tex_info.tex_id,
+            layer: tex_info.layer,
+            left: bounds.origin_x() as i16,
+            top: -bounds.origin_y() as i16,
+            width: bounds.width() as i16,
+            height: bounds.height() as i16,
+            uv_left: tex_info.uv_left,
+            uv_bot: tex_info.uv_bot,
+            uv_width: tex_info.uv_width,
+            uv_height: tex_info.uv_height,
+            colored,
+        }
+    }
+
+    // where the bitmap sits relative to the pen, with y pointing down
+    fn bounds(&self) -> RectI {
+        RectI::new(
+            Vector2I::new(self.left as i32, -self.top as i32),
+            Vector2I::new(self.width as i32, self.height as i32),
+        )
+    }
+
+    fn tex_rect(&self) -> RectI {
+        RectI::new(
+            Vector2I::new(self.uv_left as i32, self.uv_bot as i32),
+            Vector2I::new(self.width as i32, self.height as i32),
+        )
+    }
+}
+
+struct CachedGlyph {
+    glyph: Glyph,
+    // frame the glyph was last drawn in
+    last_used: u64,
+}
+
+/// Vertical metrics of the primary font at some size, in pixels
+#[derive(Copy, Clone, Debug)]
+pub struct LineMetrics {
+    pub ascent: f32,
+    // negative, as reported by the font
+    pub descent: f32,
+    pub line_height: f32,
+    // width of a cell when drawing on a grid, the advance of '0'
+    pub cell_width: f32,
+    // center of the line up from the baseline, negative below it
+    pub underline_position: f32,
+    pub underline_thickness: f32,
+    pub strikeout_position: f32,
+}
+
+/// How glyph edges are smoothed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Antialiasing {
+    #[default]
+    Grayscale,
+    // per channel coverage for horizontal RGB LCD panels
+    Subpixel,
+}
+
+/// How glyph outlines are fitted to the pixel grid
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Hinting {
+    // keeps glyphs their designed shapes, but blurrier
+    #[default]
+    None,
+    // only snaps horizontal edges, so spacing stays the same
+    Vertical,
+    // whatever the font's own hinting or the autohinter does
+    Full,
+}
+
+/// Everything about how non-color glyphs are rasterized. distance fields
+/// are always generated from unhinted outlines and only follow the format
+/// of `antialiasing`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RasterOptions {
+    pub hinting: Hinting,
+    pub antialiasing: Antialiasing,
+    // coverage is raised to 1 / `gamma`, so above 1 makes text heavier
+    pub gamma: f32,
+    // from 0, leaving coverage alone, to 1, pushing it towards fully on or
+    // off for sharper edges
+    pub contrast: f32,
+    // embolden outlines slightly, most at small sizes, so thin stems don't
+    // wash out
+    pub stem_darkening: bool,
+}
+
+impl Default for RasterOptions {
+    fn default() -> Self {
+        Self {
+            hinting: Hinting::default(),
+            antialiasing: Antialiasing::default(),
+            gamma: 1.0,
+            contrast: 0.0,
+            stem_darkening: false,
+        }
+    }
+}
+
+impl Antialiasing {
+    // format of the atlases holding non-color glyphs
+    fn mask_format(self) -> Format {
+        match self {
+            Antialiasing::Grayscale => Format::A8,
+            Antialiasing::Subpixel => Format::Rgb24,
+        }
+    }
+}
+
+/// Index of a loaded font
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct FontKey(usize);
+
+impl FontKey {
+    // glyphs drawn by `builtin` rather than taken from a font, with the char
+    // as their id
+    const BUILTIN: Self = Self(u32::MAX as usize);
+}
+
+/// Font size in pixels, quantized so it can be part of a cache key
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FontSize(u16);
+
+impl FontSize {
+    // sizes are stored in quarter pixels
+    const STEPS_PER_PX: f32 = 4.0;
+
+    pub fn new(px: f32) -> Self {
+        let steps = (px * Self::STEPS_PER_PX).round();
+        Self(steps.clamp(1.0, u16::MAX as f32) as u16)
+    }
+
+    pub fn as_px(self) -> f32 {
+        self.0 as f32 / Self::STEPS_PER_PX
+    }
+}
+
+/// Horizontal positions glyphs are rasterized at within a pixel, so text
+/// isn't spaced out to whole pixels
+pub const SUBPIXEL_PHASES: u8 = 4;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct GlyphKey {
+    pub font: FontKey,
+    pub glyph_id: u32,
+    pub size: FontSize,
+    // a distance field generated at `size` rather than a regular bitmap
+    pub sdf: bool,
+    // rasterized this many `1 / SUBPIXEL_PHASES` of a pixel to the right,
+    // always 0 for distance fields, color and builtin glyphs
+    pub phase: u8,
+}
+
+/// split `x` into whole pixels and the nearest subpixel phase for the rest
+pub fn subpixel_phase(x: f32) -> (f32, u8) {
+    let whole = x.floor();
+    let phase = ((x - whole) * SUBPIXEL_PHASES as f32).round() as u8;
+    // closer to the next pixel than to the last phase
+    if phase == SUBPIXEL_PHASES {
+        (whole + 1.0, 0)
+    } else {
+        (whole, phase)
+    }
+}
+
+/// Which face of the primary or monospace family to draw with
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct FontStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub monospace: bool,
+}
+
+impl FontStyle {
+    pub const REGULAR: Self = Self::new(false, false);
+    pub const BOLD: Self = Self::new(true, false);
+    pub const ITALIC: Self = Self::new(false, true);
+    pub const BOLD_ITALIC: Self = Self::new(true, true);
+    pub const MONOSPACE: Self = Self {
+        monospace: true,
+        ..Self::REGULAR
+    };
+
+    // every regular face comes before the other ones in its family
+    const ALL: [Self; 8] = [
+        Self::REGULAR,
+        Self::BOLD,
+        Self::ITALIC,
+        Self::BOLD_ITALIC,
+        Self::MONOSPACE,
+        Self::BOLD.with_monospace(),
+        Self::ITALIC.with_monospace(),
+        Self::BOLD_ITALIC.with_monospace(),
+    ];
+
+    pub const fn new(bold: bool, italic: bool) -> Self {
+        Self {
+            bold,
+            italic,
+            monospace: false,
+        }
+    }
+
+    const fn with_monospace(self) -> Self {
+        Self {
+            monospace: true,
+            ..self
+        }
+    }
+
+    fn index(self) -> usize {
+        self.bold as usize
+            | (self.italic as usize) << 1
+            | (self.monospace as usize) << 2
+    }
+
+    // the regular face of the same family
+    fn family_regular(self) -> Self {
+        Self {
+            monospace: self.monospace,
+            ..Self::REGULAR
+        }
+    }
+
+    fn family(self) -> FamilyName {
+        if self.monospace {
+            FamilyName::Monospace
+        } else {
+            FamilyName::SansSerif
+        }
+    }
+
+    fn properties(self) -> Properties {
+        let mut properties = Properties::new();
+        if self.bold {
+            properties.weight(Weight::BOLD);
+        }
+        if self.italic {
+            properties.style(Style::Italic);
+        }
+        properties
+    }
+}
+
+// empty pixels between glyphs in an atlas
+const ATLAS_PADDING: i32 = 1;
+
+// texture memory atlases may take up before unused glyphs are evicted
+const DEFAULT_ATLAS_BUDGET: usize = 64 << 20;
+
+// families tried in order for characters the primary font is missing. each
+// slot uses the first family that is installed
+const FALLBACK_FAMILIES: &[&[&str]] = &[
+    // symbols
+    &[
+        "Noto Sans Symbols",
+        "Noto Sans Symbols2",
+        "Noto Sans Math",
+        "DejaVu Sans",
+        "Symbola",
+    ],
+    // cjk
+    &[
+        "Noto Sans CJK SC",
+        "Source Han Sans SC",
+        "WenQuanYi Micro Hei",
+        "Droid Sans Fallback",
+    ],
+    // emoji
+    &[
+        "Noto Color Emoji",
+        "Twemoji",
+        "Apple Color Emoji",
+        "Segoe UI Emoji",
+    ],
+];
+
+struct LoadedFont {
+    // where the font was loaded from, to tell whether it's loaded already
+    handle: Handle,
+    font: Font,
+    shaper: Shaper,
+    // whether to try rasterizing glyphs in color first
+    colored: bool,
+    metrics: Metrics,
+    // unscaled advance of '0', or a guess if there isn't one
+    cell_advance: f32,
+    // of the font file, see `hash`
+    hash: OnceCell<u64>,
+}
+
+impl LoadedFont {
+    fn new(
+        key: FontKey,
+        handle: &Handle,
+        font: Font,
+    ) -> Result<Self, GlyphCacheError> {
+        let font_index = match handle {
+            Handle::Path { font_index, .. } => *font_index,
+            Handle::Memory { font_index, .. } => *font_index,
+        };
+        let data = font.copy_font_data().ok_or(GlyphCacheError::NoFontData)?;
+        let units_per_em = font.metrics().units_per_em;
+        let shaper = Shaper::new(key, data, font_index, units_per_em);
+
+        let colored = raster::has_color(&font);
+        let metrics = font.metrics();
+        let cell_advance = font
+            .glyph_for_char('0')
+            .and_then(|id| font.advance(id).ok())
+            .map(|advance| advance.x())
+            // a reasonable guess for fonts without digits
+            .unwrap_or(metrics.units_per_em as f32 / 2.0);
+
+        Ok(Self {
+            handle: handle.clone(),
+            font,
+            shaper,
+            colored,
+            metrics,
+            cell_advance,
+            hash: OnceCell::new(),
+        })
+    }
+
+    // of the font file, glyphs cached on disk are only valid for the same
+    // fonts. only computed once it's needed since large fonts take a while
+    // to hash
+    fn hash(&self) -> u64 {
+        *self.hash.get_or_init(|| {
+            let mut hasher = FnvHasher::default();
+            if let Some(data) = self.font.copy_font_data() {
+                hasher.write(&data);
+            }
+            hasher.finish()
+        })
+    }
+
+    // whether `handle` refers to the face this was loaded from
+    fn loaded_from(&self, handle: &Handle) -> bool {
+        match (&self.handle, handle) {
+            (
+                Handle::Path { path, font_index },
+                Handle::Path {
+                    path: other_path,
+                    font_index: other_index,
+                },
+            ) => path == other_path && font_index == other_index,
+            (
+                Handle::Memory { bytes, font_index },
+                Handle::Memory {
+                    bytes: other_bytes,
+                    font_index: other_index,
+                },
+            ) => Arc::ptr_eq(bytes, other_bytes) && font_index == other_index,
+            _ => false,
+        }
+    }
+
+    fn has_glyph(&self, c: char) -> bool {
+        matches!(self.font.glyph_for_char(c), Some(id) if id != 0)
+    }
+}
+
 pub struct GlyphCache {
-    cache: HashMap<u32, GlyphTexInfo, BuildHasherDefault<FnvHasher>>,
+    cache: HashMap<GlyphKey, CachedGlyph, BuildHasherDefault<FnvHasher>>,
+    // bumped by `end_frame`, used to find glyphs that haven't been drawn in
+    // a while
+    frame: u64,
+    // bumped whenever glyphs move or go away, see `generation`
+    generation: u64,
+    // in bytes, see `DEFAULT_ATLAS_BUDGET`
+    atlas_budget: usize,
     pub atlases: Vec<Atlas>,
-    font: Font,
-    font_size: f32,
+    // created on demand, most fonts don't have any color glyphs
+    pub color_atlases: Vec<Atlas>,
+    // one per glyph too large to share an atlas
+    large_glyphs: Vec<Atlas>,
+    atlas_limits: AtlasLimits,
+    fonts: Vec<LoadedFont>,
+    // primary and monospace family, indexed by `FontStyle::index`
+    faces: [FontKey; 8],
+    // tried in order for characters the primary family doesn't have
+    fallbacks: Vec<FontKey>,
+    options: RasterOptions,
 }
 
 impl GlyphCache {
-    pub fn new(font_size: f32) -> Result<Self, GlyphCacheError> {
-        let font_handle = SystemSource::new()
-            .select_best_match(&[FamilyName::SansSerif], &Properties::new())?;
-        let font = font_handle.load()?;
+    /// `layered` lets atlases add texture array layers once they're as large
+    /// as they can get, so they rarely fill up
+    pub fn new(
+        options: RasterOptions,
+        layered: bool,
+    ) -> Result<Self, GlyphCacheError> {
+        let source = SystemSource::new();
+        let mut fonts = Vec::new();
 
-        let atlases = vec![Atlas::new()];
+        let regular = source.select_best_match(
+            &[FamilyName::SansSerif],
+            &FontStyle::REGULAR.properties(),
+        )?;
+        let regular = add_font(&mut fonts, &regular)?;
+        let mut faces = [regular; 8];
+
+        for style in &FontStyle::ALL[1..] {
+            // without a dedicated face the style is drawn as the regular
+            // face of its family, or the primary one. we don't synthesize
+            // bold or oblique
+            let face = source
+                .select_best_match(&[style.family()], &style.properties())
+                .map_err(GlyphCacheError::from)
+                .and_then(|handle| add_font(&mut fonts, &handle));
+            faces[style.index()] =
+                face.unwrap_or(faces[style.family_regular().index()]);
+        }
+
+        let mut fallbacks = Vec::new();
+        for families in FALLBACK_FAMILIES {
+            // a missing fallback just means those characters render as tofu
+            if let Ok(key) = load_fallback(&source, &mut fonts, families) {
+                if !faces.contains(&key) && !fallbacks.contains(&key) {
+                    fallbacks.push(key);
+                }
+            }
+        }
+
+        let atlas_limits = AtlasLimits::query(layered);
+        let atlases = vec![Atlas::new(
+            options.antialiasing.mask_format(),
+            ATLAS_PADDING,
+            atlas_limits,
+        )];
 
         Ok(Self {
             cache: HashMap::default(),
+            frame: 0,
+            generation: 0,
+            atlas_budget: DEFAULT_ATLAS_BUDGET,
             atlases,
-            font,
-            font_size,
+            color_atlases: Vec::new(),
+            large_glyphs: Vec::new(),
+            atlas_limits,
+            fonts,
+            faces,
+            fallbacks,
+            options,
         })
     }
 
-    pub fn get(
+    /// metrics of the face for `style`
+    pub fn metrics(&self, style: FontStyle, size: FontSize) -> LineMetrics {
+        let font = &self.fonts[self.faces[style.index()].0];
+        let metrics = &font.metrics;
+        let scale = size.as_px() / metrics.units_per_em as f32;
+        // fonts without these leave them at 0
+        let underline_thickness = if metrics.underline_thickness > 0.0 {
+            metrics.underline_thickness
+        } else {
+            metrics.units_per_em as f32 / 16.0
+        };
+        // font-kit doesn't read the strikeout position, halfway up lowercase
+        // letters is where fonts tend to put it
+        let strikeout_position = if metrics.x_height > 0.0 {
+            metrics.x_height / 2.0
+        } else {
+            metrics.ascent / 3.0
+        };
+        LineMetrics {
+            ascent: metrics.ascent * scale,
+            descent: metrics.descent * scale,
+            line_height: (metrics.ascent - metrics.descent + metrics.line_gap)
+                * scale,
+            cell_width: font.cell_advance * scale,
+            underline_position: metrics.underline_position * scale,
+            underline_thickness: underline_thickness * scale,
+            strikeout_position: strikeout_position * scale,
+        }
+    }
+
+    /// the face for `style`, or the first fallback that has a glyph for `c`
+    /// if that face doesn't
+    pub fn font_for_char(&self, c: char, style: FontStyle) -> FontKey {
+        let face = self.faces[style.index()];
+        if self.fonts[face.0].has_glyph(c) {
+            return face;
+        }
+
+        self.fallbacks
+            .iter()
+            .copied()
+            .find(|key| self.fonts[key.0].has_glyph(c))
+            // the face's .notdef is what we draw for missing chars
+            .unwrap_or(face)
+    }
+
+    pub fn glyph_for_char(
+        &self,
+        c: char,
+        style: FontStyle,
+        size: FontSize,
+    ) -> GlyphKey {
+        let font = self.font_for_char(c, style);
+        GlyphKey {
+            font,
+            glyph_id: self.fonts[font.0].font.glyph_for_char(c).unwrap_or(0),
+            size,
+            sdf: false,
+            phase: 0,
+        }
+    }
+
+    /// like `glyph_for_char`, but box drawing, block elements and braille
+    /// are drawn to fill a cell of the monospace font exactly, so they join
+    /// up with their neighbours on a grid
+    pub fn glyph_for_cell(
+        &self,
+        c: char,
+        style: FontStyle,
+        size: FontSize,
+    ) -> GlyphKey {
+        if !builtin::is_builtin(c) {
+            return self.glyph_for_char(c, style, size);
+        }
+
+        GlyphKey {
+            font: FontKey::BUILTIN,
+            glyph_id: c as u32,
+            size,
+            sdf: false,
+            phase: 0,
+        }
+    }
+
+    /// shape a run of text in one style and direction into glyphs
+    /// positioned relative to its start, splitting it further by the font
+    /// that covers each character
+    pub fn shape(
         &mut self,
-        glyph_id: u32,
-    ) -> Result<GlyphTexInfo, GlyphLoadingError> {
-        if let Some(glyph) = self.cache.get(&glyph_id) {
-            return Ok(*glyph);
-        }
-
-        let bounds = self.font.raster_bounds(
-            glyph_id,
-            self.font_size,
-            Default::default(),
-            HintingOptions::None,
-            RasterizationOptions::GrayscaleAa,
-        )?;
+        text: &str,
+        style: FontStyle,
+        size: FontSize,
+        direction: Direction,
+    ) -> Vec<ShapedGlyph> {
+        let mut runs: Vec<(usize, FontKey)> = Vec::new();
+
+        for (i, c) in text.char_indices() {
+            let font = match runs.last() {
+                Some(&(_, font)) if continues_cluster(c) => font,
+                _ => self.font_for_char(c, style),
+            };
 
-        let (transform, glyph) = self.load_glyph(&bounds);
-        let font_size = self.font_size;
-        let atlas = &mut self.atlases.last_mut().unwrap();
-        let font = &mut self.font;
-
-        // println!(
-        //     "{:?} {:?} {:?}\n{:?}\n{:?}\n",
-        //     bounds.upper_right(),
-        //     bounds.lower_left(),
-        //     bounds.lower_right(),
-        //     transform,
-        //     glyph
-        // );
-
-        font.rasterize_glyph(
-            &mut atlas.canvas,
-            glyph_id,
+            match runs.last() {
+                Some(&(_, run_font)) if run_font == font => (),
+                _ => runs.push((i, font)),
+            }
+        }
+
+        let ends = runs.iter().skip(1).map(|&(start, _)| start);
+        let mut runs: Vec<_> = runs
+            .iter()
+            .zip(ends.chain([text.len()]))
+            .map(|(&(start, font), end)| (start..end, font))
+            .collect();
+        // right to left runs start on the right
+        if direction == Direction::RightToLeft {
+            runs.reverse();
+        }
+
+        let mut glyphs = Vec::with_capacity(text.len());
+        for (range, font) in runs {
+            let start = range.start;
+            let shaped =
+                self.fonts[font.0]
+                    .shaper
+                    .shape(&text[range], size, direction);
+            glyphs.extend(shaped.into_iter().map(|glyph| ShapedGlyph {
+                cluster: start + glyph.cluster,
+                ..glyph
+            }));
+        }
+
+        glyphs
+    }
+
+    pub fn get(&mut self, key: GlyphKey) -> Result<Glyph, GlyphLoadingError> {
+        if let Some(cached) = self.cache.get_mut(&key) {
+            cached.last_used = self.frame;
+            return Ok(cached.glyph);
+        }
+
+        if key.font == FontKey::BUILTIN {
+            let metrics = self.metrics(FontStyle::MONOSPACE, key.size);
+            let mut glyph = char::from_u32(key.glyph_id)
+                .and_then(|c| builtin::rasterize(c, &metrics))
+                .ok_or(GlyphLoadingError::NoSuchGlyph)?;
+            self.convert_to_mask_format(&mut glyph);
+            return Ok(self.insert_rasterized(key, &glyph, false));
+        }
+
+        // color glyphs are bitmaps, which can't be moved by part of a pixel
+        if self.fonts[key.font.0].colored && key.phase != 0 {
+            return self.get(GlyphKey { phase: 0, ..key });
+        }
+
+        let font = &self.fonts[key.font.0];
+        let font_size = key.size.as_px();
+        if key.sdf {
+            let mut glyph =
+                sdf::rasterize_sdf(&font.font, key.glyph_id, font_size)
+                    .ok_or(GlyphLoadingError::NoSuchGlyph)?;
+            self.convert_to_mask_format(&mut glyph);
+            return Ok(self.insert_rasterized(key, &glyph, false));
+        }
+
+        if font.colored {
+            if let Some(glyph) =
+                raster::rasterize_color(&font.font, key.glyph_id, font_size)
+            {
+                return Ok(self.insert_rasterized(key, &glyph, true));
+            }
+        }
+
+        let glyph = raster::rasterize_mask(
+            &font.font,
+            key.glyph_id,
             font_size,
-            transform,
-            HintingOptions::None,
-            RasterizationOptions::GrayscaleAa,
-        )?;
+            key.phase as f32 / SUBPIXEL_PHASES as f32,
+            &self.options,
+        )
+        .ok_or(GlyphLoadingError::NoSuchGlyph)?;
+        Ok(self.insert_rasterized(key, &glyph, false))
+    }
+
+    // single channel glyphs go in every channel of subpixel atlases
+    fn convert_to_mask_format(&self, glyph: &mut RasterizedGlyph) {
+        if self.options.antialiasing.mask_format() == Format::Rgb24 {
+            glyph.pixels =
+                glyph.pixels.iter().flat_map(|&c| [c, c, c]).collect();
+        }
+    }
+
+    // pack a rasterized glyph into an atlas
+    fn insert_rasterized(
+        &mut self,
+        key: GlyphKey,
+        glyph: &RasterizedGlyph,
+        colored: bool,
+    ) -> Glyph {
+        let tex_info = self.load_glyph(glyph, colored);
+        let glyph = Glyph::new(&glyph.bounds, &tex_info, colored);
+        self.cache.insert(
+            key,
+            CachedGlyph {
+                glyph,
+                last_used: self.frame,
+            },
+        );
+        glyph
+    }
+
+    /// changes whenever glyphs are moved to another spot in the atlases or
+    /// evicted, anything holding on to a `Glyph` from before has to get it
+    /// again
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// drop every cached glyph along with the atlases holding them
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        self.cache.clear();
+        self.atlases.clear();
+        self.push_atlas(false);
+        self.color_atlases.clear();
+        self.large_glyphs.clear();
+    }
+
+    /// glyphs rasterized with the old options are thrown away, returns
+    /// whether there were any changes
+    pub fn set_raster_options(&mut self, options: RasterOptions) -> bool {
+        if options == self.options {
+            return false;
+        }
+
+        self.options = options;
+        self.clear();
+        true
+    }
 
-        Ok(*self.cache.entry(glyph_id).or_insert(glyph))
+    pub fn set_atlas_budget(&mut self, bytes: usize) {
+        self.atlas_budget = bytes;
     }
 
-    pub fn cache_common(&mut self) {
+    /// counts glyphs drawn without going through `get` as used this frame,
+    /// so they aren't evicted while they're on screen
+    pub fn mark_used(&mut self, keys: &[GlyphKey]) {
+        for key in keys {
+            // color glyphs are only cached at phase 0, see `get`
+            let cached = match self.cache.get_mut(key) {
+                Some(cached) => Some(cached),
+                None => self.cache.get_mut(&GlyphKey { phase: 0, ..*key }),
+            };
+            if let Some(cached) = cached {
+                cached.last_used = self.frame;
+            }
+        }
+    }
+
+    /// called once everything has been drawn. once atlases take up more
+    /// than the budget, glyphs that weren't used this frame are evicted
+    /// starting with the least recently used, and the remaining ones are
+    /// repacked if that left the atlases mostly empty
+    pub fn end_frame(&mut self) {
+        if self.atlas_memory() > self.atlas_budget {
+            // leave some room so this doesn't happen again right away
+            let evicted = self.evict(self.atlas_budget / 2);
+            let compact = self.glyph_memory() * 2 < self.atlas_memory()
+                && self.can_compact();
+            if compact {
+                self.compact();
+            }
+
+            // instances made before stay valid unless glyphs were dropped or
+            // moved
+            if evicted > 0 || compact {
+                self.generation += 1;
+            }
+        }
+
+        self.frame += 1;
+    }
+
+    fn atlas_memory(&self) -> usize {
+        self.atlases
+            .iter()
+            .chain(&self.color_atlases)
+            .chain(&self.large_glyphs)
+            .map(Atlas::memory_usage)
+            .sum()
+    }
+
+    // texture memory the cached glyphs themselves take up
+    fn glyph_memory(&self) -> usize {
+        self.cache
+            .values()
+            .map(|cached| self.glyph_size(&cached.glyph))
+            .sum()
+    }
+
+    fn glyph_size(&self, glyph: &Glyph) -> usize {
+        let bytes_per_pixel = self.format(glyph.colored).bytes_per_pixel();
+        let width = (glyph.width as i32 + ATLAS_PADDING) as usize;
+        let height = (glyph.height as i32 + ATLAS_PADDING) as usize;
+        width * height * bytes_per_pixel as usize
+    }
+
+    // drop glyphs not used this frame, least recently used first, until the
+    // rest take up no more than `target` bytes. returns how many were dropped
+    fn evict(&mut self, target: usize) -> usize {
+        let mut cold: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| cached.last_used < self.frame)
+            .map(|(key, cached)| (*key, cached.last_used))
+            .collect();
+        cold.sort_unstable_by_key(|(_, last_used)| *last_used);
+
+        let mut memory = self.glyph_memory();
+        let mut evicted = 0;
+        for (key, _) in cold {
+            if memory <= target {
+                break;
+            }
+
+            let glyph = self.cache.remove(&key).unwrap().glyph;
+            memory -= self.glyph_size(&glyph);
+            // large glyphs are alone in their texture
+            self.large_glyphs
+                .retain(|atlas| atlas.tex_id != glyph.tex_id);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    // whether `compact` could leave the shared atlases any smaller. with a
+    // small budget, new atlases alone may take up more than it
+    fn can_compact(&self) -> bool {
+        self.atlases.len() > 1
+            || self.color_atlases.len() > 1
+            || self
+                .atlases
+                .iter()
+                .chain(&self.color_atlases)
+                .any(Atlas::is_grown)
+    }
+
+    // move every glyph in a shared atlas into new tightly packed atlases,
+    // reclaiming the space left by evicted ones
+    fn compact(&mut self) {
+        let old_atlases: Vec<Atlas> = mem::take(&mut self.atlases)
+            .into_iter()
+            .chain(mem::take(&mut self.color_atlases))
+            .collect();
+        self.push_atlas(false);
+
+        let mut keys: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| {
+                let tex_id = cached.glyph.tex_id;
+                old_atlases.iter().any(|atlas| atlas.tex_id == tex_id)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        // tallest first so shelves end up evenly filled
+        keys.sort_unstable_by_key(|key| Reverse(self.cache[key].glyph.height));
+
+        for key in keys {
+            let glyph = self.cache[&key].glyph;
+            let atlas = old_atlases
+                .iter()
+                .find(|atlas| atlas.tex_id == glyph.tex_id)
+                .unwrap();
+
+            let rasterized = RasterizedGlyph {
+                bounds: glyph.bounds(),
+                pixels: atlas.read(glyph.layer, glyph.tex_rect()),
+            };
+            let tex_info = self.load_glyph(&rasterized, glyph.colored);
+            self.cache.get_mut(&key).unwrap().glyph =
+                Glyph::new(&rasterized.bounds, &tex_info, glyph.colored);
+        }
+    }
+
+    pub fn cache_common(&mut self, size: FontSize) {
+        // glyphs saved by an earlier run include these, and likely most of
+        // what's going to be drawn
+        if self.cache.is_empty() && self.load(size).is_ok() {
+            unsafe {
+                self.update_textures();
+            }
+            return;
+        }
+
         for i in 32u8..127u8 {
-            // println!("{}", i as char);
-            let glyph_id = self.font.glyph_for_char(i as char).unwrap();
-            self.get(glyph_id);
+            // printable ascii is only a warmup, failures show up on use
+            let _ = self.get(self.glyph_for_char(
+                i as char,
+                FontStyle::REGULAR,
+                size,
+            ));
         }
-        let cur = self.current_atlas();
         unsafe {
-            cur.update_texture();
+            self.update_textures();
+        }
+    }
+
+    // identifies glyphs rasterized from the same fonts at the same base size
+    // with the same options into atlases with the same limits
+    fn disk_cache_key(&self, size: FontSize) -> u64 {
+        let mut hasher = FnvHasher::default();
+        for font in &self.fonts {
+            hasher.write_u64(font.hash());
+        }
+        hasher.write_u16(size.0);
+
+        let options = &self.options;
+        hasher.write_u8(options.hinting as u8);
+        hasher.write_u8(options.antialiasing as u8);
+        hasher.write_u32(options.gamma.to_bits());
+        hasher.write_u32(options.contrast.to_bits());
+        hasher.write_u8(options.stem_darkening as u8);
+
+        hasher.write_i32(self.atlas_limits.max_size);
+        hasher.write_i32(self.atlas_limits.max_layers);
+        hasher.finish()
+    }
+
+    /// write every cached glyph along with the atlases holding them to the
+    /// cache directory, to be picked up by `cache_common` with the same
+    /// base `size` on the next run
+    pub fn save(&self, size: FontSize) -> io::Result<()> {
+        disk_cache::save(self.disk_cache_key(size), |w| {
+            let lists =
+                [&self.atlases, &self.color_atlases, &self.large_glyphs];
+            for atlases in lists {
+                write_u32(w, atlases.len() as u32)?;
+                for atlas in atlases {
+                    atlas.write_to(w)?;
+                }
+            }
+
+            write_u32(w, self.cache.len() as u32)?;
+            for (key, cached) in &self.cache {
+                let glyph = &cached.glyph;
+                // texture names are different every run, so glyphs refer to
+                // their atlas by position instead
+                let (list, index) = lists
+                    .iter()
+                    .enumerate()
+                    .find_map(|(list, atlases)| {
+                        let index = atlases
+                            .iter()
+                            .position(|atlas| atlas.tex_id == glyph.tex_id)?;
+                        Some((list, index))
+                    })
+                    .expect("cached glyph without an atlas");
+
+                write_u32(w, key.font.0 as u32)?;
+                write_u32(w, key.glyph_id)?;
+                write_u16(w, key.size.0)?;
+                write_u8(w, key.sdf as u8)?;
+                write_u8(w, key.phase)?;
+
+                write_u8(w, list as u8)?;
+                write_u32(w, index as u32)?;
+                write_u16(w, glyph.layer)?;
+                write_i16(w, glyph.left)?;
+                write_i16(w, glyph.top)?;
+                write_i16(w, glyph.width)?;
+                write_i16(w, glyph.height)?;
+                write_f32(w, glyph.uv_left)?;
+                write_f32(w, glyph.uv_bot)?;
+                write_f32(w, glyph.uv_width)?;
+                write_f32(w, glyph.uv_height)?;
+                write_u8(w, glyph.colored as u8)?;
+            }
+            Ok(())
+        })
+    }
+
+    // replace the cache and atlases with the ones saved for `size`, leaving
+    // them alone if there are none or they don't match the current fonts
+    // and options
+    fn load(&mut self, size: FontSize) -> io::Result<()> {
+        let mut r = disk_cache::open(self.disk_cache_key(size))
+            .ok_or_else(|| invalid_data("no matching glyph cache"))?;
+
+        let mut lists: [Vec<Atlas>; 3] = Default::default();
+        for atlases in &mut lists {
+            for _ in 0..read_u32(&mut r)? {
+                atlases.push(Atlas::read_from(&mut r)?);
+            }
+        }
+        if lists[0].is_empty() {
+            return Err(invalid_data("no mask atlas"));
+        }
+
+        let mut cache = HashMap::default();
+        for _ in 0..read_u32(&mut r)? {
+            let (key, glyph) = read_glyph(&mut r, &lists)?;
+            if key.font != FontKey::BUILTIN && key.font.0 >= self.fonts.len() {
+                return Err(invalid_data("glyph from an unknown font"));
+            }
+            cache.insert(
+                key,
+                CachedGlyph {
+                    glyph,
+                    last_used: self.frame,
+                },
+            );
+        }
+
+        let [atlases, color_atlases, large_glyphs] = lists;
+        self.atlases = atlases;
+        self.color_atlases = color_atlases;
+        self.large_glyphs = large_glyphs;
+        self.cache = cache;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// upload any glyphs rasterized since the last call to the GPU
+    pub unsafe fn update_textures(&mut self) {
+        let atlases = self
+            .atlases
+            .iter_mut()
+            .chain(&mut self.color_atlases)
+            .chain(&mut self.large_glyphs);
+        for atlas in atlases {
+            atlas.update_texture();
         }
     }
 
     fn load_glyph(
         &mut self,
-        glyph_bounds: &RectI,
-    ) -> (Transform2F, GlyphTexInfo) {
-        let cur_atlas = self.current_atlas();
-        match cur_atlas.insert(glyph_bounds) {
-            Ok((transform, glyph)) => (transform, glyph),
+        glyph: &RasterizedGlyph,
+        colored: bool,
+    ) -> GlyphTexInfo {
+        let size = glyph.bounds.size();
+        let cur_atlas = self.current_atlas(colored);
+        match cur_atlas.insert(size, &glyph.pixels) {
+            Ok(tex_info) => tex_info,
             Err(AtlasInsertError::Full) => {
-                // we're done with this atlas so upload anything pending to gpu
-                unsafe {
-                    cur_atlas.update_texture();
-                }
-                self.atlases.push(Atlas::new());
-                self.load_glyph(glyph_bounds)
+                self.push_atlas(colored);
+                self.load_glyph(glyph, colored)
             }
-            Err(AtlasInsertError::GlyphTooLarge) => panic!("glyph too large"),
+            Err(AtlasInsertError::GlyphTooLarge) => {
+                // textures larger than the driver allows fail to allocate,
+                // leaving the glyph blank
+                let mut atlas = Atlas::new(
+                    self.format(colored),
+                    0,
+                    AtlasLimits::single(size),
+                );
+                let tex_info = atlas
+                    .insert(size, &glyph.pixels)
+                    .expect("atlas sized for the glyph");
+                self.large_glyphs.push(atlas);
+                tex_info
+            }
+        }
+    }
+
+    fn push_atlas(&mut self, colored: bool) {
+        let atlas =
+            Atlas::new(self.format(colored), ATLAS_PADDING, self.atlas_limits);
+        if colored {
+            self.color_atlases.push(atlas);
+        } else {
+            self.atlases.push(atlas);
+        }
+    }
+
+    fn format(&self, colored: bool) -> Format {
+        if colored {
+            Format::Rgba32
+        } else {
+            self.options.antialiasing.mask_format()
         }
     }
 
-    fn current_atlas(&mut self) -> &mut Atlas {
+    fn current_atlas(&mut self, colored: bool) -> &mut Atlas {
+        if colored && self.color_atlases.is_empty() {
+            self.push_atlas(true);
+        }
+
+        let atlases = if colored {
+            &mut self.color_atlases
+        } else {
+            &mut self.atlases
+        };
+
         // should never be able to not have an atlas
-        self.atlases.last_mut().unwrap()
+        atlases.last_mut().unwrap()
+    }
+}
+
+// a glyph written by `GlyphCache::save`, in one of the atlases in `lists`
+fn read_glyph(
+    r: &mut impl Read,
+    lists: &[Vec<Atlas>; 3],
+) -> io::Result<(GlyphKey, Glyph)> {
+    let key = GlyphKey {
+        font: FontKey(read_u32(r)? as usize),
+        glyph_id: read_u32(r)?,
+        size: FontSize(read_u16(r)?),
+        sdf: read_u8(r)? != 0,
+        phase: read_u8(r)?,
+    };
+
+    let list = read_u8(r)? as usize;
+    let index = read_u32(r)? as usize;
+    let atlas = lists
+        .get(list)
+        .and_then(|atlases| atlases.get(index))
+        .ok_or_else(|| invalid_data("glyph in an unknown atlas"))?;
+
+    let glyph = Glyph {
+        tex_id: atlas.tex_id,
+        layer: read_u16(r)?,
+        left: read_i16(r)?,
+        top: read_i16(r)?,
+        width: read_i16(r)?,
+        height: read_i16(r)?,
+        uv_left: read_f32(r)?,
+        uv_bot: read_f32(r)?,
+        uv_width: read_f32(r)?,
+        uv_height: read_f32(r)?,
+        colored: read_u8(r)? != 0,
+    };
+
+    // the texture coordinates are drawn from and read back by `compact`
+    let uv_matches = glyph.uv_left.fract() == 0.0
+        && glyph.uv_bot.fract() == 0.0
+        && glyph.uv_width == glyph.width as f32
+        && glyph.uv_height == glyph.height as f32;
+    if !uv_matches || !atlas.contains(glyph.layer, glyph.tex_rect()) {
+        return Err(invalid_data("glyph outside of its atlas"));
+    }
+    Ok((key, glyph))
+}
+
+// load a font unless it's already loaded, returning its key either way
+fn add_font(
+    fonts: &mut Vec<LoadedFont>,
+    handle: &Handle,
+) -> Result<FontKey, GlyphCacheError> {
+    // styles and fallbacks often resolve to the same face, which is
+    // better found before shaping and measuring it all over again
+    if let Some(existing) = fonts.iter().position(|f| f.loaded_from(handle)) {
+        return Ok(FontKey(existing));
+    }
+
+    // the same face may also be installed under another path
+    let font = handle.load()?;
+    let name = font.postscript_name();
+    if let Some(existing) =
+        fonts.iter().position(|f| f.font.postscript_name() == name)
+    {
+        return Ok(FontKey(existing));
+    }
+
+    let key = FontKey(fonts.len());
+    fonts.push(LoadedFont::new(key, handle, font)?);
+    Ok(key)
+}
+
+// load the first installed family out of `families`
+fn load_fallback(
+    source: &impl Source,
+    fonts: &mut Vec<LoadedFont>,
+    families: &[&str],
+) -> Result<FontKey, GlyphCacheError> {
+    let families: Vec<_> = families
+        .iter()
+        .map(|name| FamilyName::Title(name.to_string()))
+        .collect();
+
+    let handle = source.select_best_match(&families, &Properties::new())?;
+    add_font(fonts, &handle)
+}
+
+// characters that attach to the previous one and should be shaped with the
+// same font even if it has no glyph of its own for them
+fn continues_cluster(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036f}' // combining diacritical marks
+        | '\u{200c}'..='\u{200d}' // zero width (non-)joiner
+        | '\u{fe00}'..='\u{fe0f}' // variation selectors
+        | '\u{1f3fb}'..='\u{1f3ff}' // emoji skin tone modifiers
+        | '\u{e0020}'..='\u{e007f}' // tag characters
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subpixel_phases() {
+        assert_eq!(subpixel_phase(0.0), (0.0, 0));
+        assert_eq!(subpixel_phase(0.124), (0.0, 0));
+        // halfway between phases rounds up
+        assert_eq!(subpixel_phase(0.125), (0.0, 1));
+        assert_eq!(subpixel_phase(0.5), (0.0, 2));
+        // closer to the next pixel than to the last phase
+        assert_eq!(subpixel_phase(0.99), (1.0, 0));
+        assert_eq!(subpixel_phase(12.75), (12.0, 3));
+        // the phase is always to the right of the whole pixels
+        assert_eq!(subpixel_phase(-0.4), (-1.0, 2));
+        assert_eq!(subpixel_phase(-0.01), (0.0, 0));
+        assert_eq!(subpixel_phase(-1.0), (-1.0, 0));
+    }
+
+    #[test]
+    fn subpixel_phases_stay_close_to_x() {
+        for i in -400..400 {
+            let x = i as f32 * 0.0173;
+            let (whole, phase) = subpixel_phase(x);
+            assert_eq!(whole.fract(), 0.0, "{}", x);
+            assert!(phase < SUBPIXEL_PHASES, "{}", x);
+            let placed = whole + phase as f32 / SUBPIXEL_PHASES as f32;
+            let max_error = 0.5 / SUBPIXEL_PHASES as f32;
+            assert!(
+                (placed - x).abs() <= max_error,
+                "{} placed at {}",
+                x,
+                placed
+            );
+        }
     }
 }
@@ -0,0 +1,336 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use bitflags::bitflags;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use super::{Decoration, FontStyle, Rgb, TextSpan};
+
+/// A grapheme drawn into a grid cell, or two cells if it's wide
+#[derive(Copy, Clone, Debug)]
+pub struct Cell<'a> {
+    pub text: &'a str,
+    pub fg: Rgb,
+    pub bg: Option<Rgb>,
+    // drawn across the whole cell
+    pub decoration: Decoration,
+    // defaults to `fg`
+    pub decoration_color: Option<Rgb>,
+}
+
+impl<'a> Cell<'a> {
+    pub fn new(text: &'a str, fg: Rgb, bg: Option<Rgb>) -> Self {
+        Self {
+            text,
+            fg,
+            bg,
+            decoration: Decoration::empty(),
+            decoration_color: None,
+        }
+    }
+
+    /// draw lines along the cell in `color`, or `fg` if `None`
+    pub fn with_decoration(
+        self,
+        decoration: Decoration,
+        color: Option<Rgb>,
+    ) -> Self {
+        Self {
+            decoration,
+            decoration_color: color,
+            ..self
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        grapheme_width(self.text)
+    }
+}
+
+bitflags! {
+    /// Face a `GridCell` is drawn with
+    #[derive(Default)]
+    pub struct CellFlags: u8 {
+        const BOLD   = 0b0000_0001;
+        const ITALIC = 0b0000_0010;
+    }
+}
+
+/// A character on a `CellGrid`, taking up two cells if it's wide
+#[derive(Copy, Clone, Debug)]
+pub struct GridCell {
+    pub c: char,
+    pub fg: Rgb,
+    pub bg: Option<Rgb>,
+    pub flags: CellFlags,
+    pub decoration: Decoration,
+    // defaults to `fg`
+    pub decoration_color: Option<Rgb>,
+}
+
+impl GridCell {
+    pub fn width(&self) -> usize {
+        self.c.width().unwrap_or(1).clamp(1, 2)
+    }
+
+    pub(super) fn style(&self) -> FontStyle {
+        FontStyle {
+            bold: self.flags.contains(CellFlags::BOLD),
+            italic: self.flags.contains(CellFlags::ITALIC),
+            monospace: true,
+        }
+    }
+}
+
+/// Lines of text on a grid of monospace cells, like a terminal, drawn by
+/// `Renderer::draw_grid`
+///
+/// lines are wrapped to the number of columns as they're drawn, so they
+/// reflow when the grid is resized. the newest lines are at the bottom once
+/// there are more than fit, and it scrolls back through up to `max_lines`
+/// of them
+#[derive(Debug)]
+pub struct CellGrid {
+    lines: VecDeque<Vec<GridCell>>,
+    max_lines: usize,
+    // in logical pixels
+    font_size: f32,
+    cols: usize,
+    rows: usize,
+    // rows scrolled back from the newest one
+    scroll: usize,
+}
+
+impl CellGrid {
+    /// an empty grid with text at `font_size` logical pixels, without any
+    /// cells until it's resized
+    pub fn new(font_size: f32, max_lines: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            // the newest line is always kept
+            max_lines: max_lines.max(1),
+            font_size,
+            cols: 0,
+            rows: 0,
+            scroll: 0,
+        }
+    }
+
+    pub fn font_size(&self) -> f32 {
+        self.font_size
+    }
+
+    /// fit as many cells of `cell_size` as possible into `width` by
+    /// `height`, all in logical pixels. see `Renderer::grid_cell_size`
+    pub fn resize(&mut self, width: f32, height: f32, cell_size: (f32, f32)) {
+        // at least a column so there's somewhere to wrap to
+        self.cols = ((width / cell_size.0).floor() as usize).max(1);
+        self.rows = (height / cell_size.1).floor().max(0.0) as usize;
+        self.scroll(0);
+    }
+
+    /// add the text of `spans` as a new line, or several if it has line
+    /// breaks, and scroll down to it. the size of the spans is ignored
+    pub fn push_line(&mut self, spans: &[TextSpan]) {
+        let mut line = Vec::new();
+        for span in spans {
+            let mut flags = CellFlags::empty();
+            flags.set(CellFlags::BOLD, span.style.bold);
+            flags.set(CellFlags::ITALIC, span.style.italic);
+
+            for c in span.text.chars() {
+                let c = match c {
+                    '\n' => {
+                        self.push_cells(std::mem::take(&mut line));
+                        continue;
+                    }
+                    '\t' => ' ',
+                    _ if c.is_control() => continue,
+                    _ => c,
+                };
+                line.push(GridCell {
+                    c,
+                    fg: span.color,
+                    bg: span.background,
+                    flags,
+                    decoration: span.decoration,
+                    decoration_color: span.decoration_color,
+                });
+            }
+        }
+        self.push_cells(line);
+        self.scroll = 0;
+    }
+
+    /// move `rows` back through older lines, or forward if it's negative
+    pub fn scroll(&mut self, rows: isize) {
+        let total: usize =
+            self.lines.iter().map(|line| self.wrap(line).len()).sum();
+        let max = total.saturating_sub(self.rows);
+        self.scroll = self.scroll.saturating_add_signed(rows).min(max);
+    }
+
+    /// the rows on screen from the top, each with the cells on it
+    pub(super) fn visible_rows(&self) -> Vec<&[GridCell]> {
+        let mut rows = Vec::with_capacity(self.rows);
+        // newest first, until the screen is full
+        for line in self.lines.iter().rev() {
+            for range in self.wrap(line).into_iter().rev() {
+                rows.push(&line[range]);
+            }
+            if rows.len() >= self.scroll + self.rows {
+                break;
+            }
+        }
+
+        let mut rows: Vec<_> =
+            rows.into_iter().skip(self.scroll).take(self.rows).collect();
+        rows.reverse();
+        rows
+    }
+
+    fn push_cells(&mut self, line: Vec<GridCell>) {
+        if self.lines.len() == self.max_lines {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    // the cells on each row `line` wraps onto, an empty line still takes up
+    // a row. wide characters aren't split across rows
+    fn wrap(&self, line: &[GridCell]) -> Vec<Range<usize>> {
+        let mut rows = Vec::new();
+        let mut start = 0;
+        let mut col = 0;
+        for (i, cell) in line.iter().enumerate() {
+            let width = cell.width();
+            if col + width > self.cols && i > start {
+                rows.push(start..i);
+                start = i;
+                col = 0;
+            }
+            col += width;
+        }
+        rows.push(start..line.len());
+        rows
+    }
+}
+
+/// number of cells a grapheme takes up on a grid, 2 for East Asian wide and
+/// fullwidth characters and emoji presentation sequences
+///
+/// anything else gets a cell, even if it would be zero width on its own,
+/// like a lone combining mark
+pub fn grapheme_width(grapheme: &str) -> usize {
+    grapheme.width().clamp(1, 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a grid of `cols` by `rows` cells
+    fn grid(cols: usize, rows: usize, max_lines: usize) -> CellGrid {
+        let mut grid = CellGrid::new(16.0, max_lines);
+        grid.resize(cols as f32 * 10.0, rows as f32 * 20.0, (10.0, 20.0));
+        grid
+    }
+
+    fn push(grid: &mut CellGrid, text: &str) {
+        let color = Rgb::new(255, 255, 255);
+        grid.push_line(&[TextSpan::new(
+            text,
+            FontStyle::MONOSPACE,
+            16.0,
+            color,
+        )]);
+    }
+
+    fn visible(grid: &CellGrid) -> Vec<String> {
+        let rows = grid.visible_rows();
+        rows.iter()
+            .map(|row| row.iter().map(|cell| cell.c).collect())
+            .collect()
+    }
+
+    #[test]
+    fn wraps_to_the_columns() {
+        let mut grid = grid(4, 10, 100);
+        push(&mut grid, "abcdefghij");
+        push(&mut grid, "");
+        push(&mut grid, "klmn");
+        assert_eq!(visible(&grid), ["abcd", "efgh", "ij", "", "klmn"]);
+
+        // lines reflow
+        grid.resize(60.0, 200.0, (10.0, 20.0));
+        assert_eq!(visible(&grid), ["abcdef", "ghij", "", "klmn"]);
+    }
+
+    #[test]
+    fn wide_characters_are_not_split() {
+        let mut grid = grid(3, 10, 100);
+        push(&mut grid, "ab\u{4e2d}c\u{6587}\u{5b57}");
+        assert_eq!(visible(&grid), ["ab", "\u{4e2d}c", "\u{6587}", "\u{5b57}"]);
+
+        // they get a row of their own even if it's too narrow
+        let mut grid = self::grid(1, 10, 100);
+        push(&mut grid, "\u{4e2d}a\u{6587}");
+        assert_eq!(visible(&grid), ["\u{4e2d}", "a", "\u{6587}"]);
+    }
+
+    #[test]
+    fn control_characters() {
+        let mut grid = grid(10, 10, 100);
+        push(&mut grid, "a\tb\u{7}c\nd");
+        assert_eq!(visible(&grid), ["a bc", "d"]);
+    }
+
+    #[test]
+    fn newest_rows_are_at_the_bottom() {
+        let mut grid = grid(4, 2, 100);
+        assert!(visible(&grid).is_empty());
+        push(&mut grid, "abcdef");
+        push(&mut grid, "gh");
+        assert_eq!(visible(&grid), ["ef", "gh"]);
+    }
+
+    #[test]
+    fn scroll_is_clamped() {
+        let mut grid = grid(4, 2, 100);
+        push(&mut grid, "abcdef");
+        push(&mut grid, "gh");
+
+        grid.scroll(1);
+        assert_eq!(visible(&grid), ["abcd", "ef"]);
+        // there's nothing above the first row
+        grid.scroll(10);
+        assert_eq!(visible(&grid), ["abcd", "ef"]);
+        grid.scroll(-10);
+        assert_eq!(visible(&grid), ["ef", "gh"]);
+
+        // pushing a line scrolls back down to it
+        grid.scroll(1);
+        push(&mut grid, "ij");
+        assert_eq!(visible(&grid), ["gh", "ij"]);
+
+        // everything fits, so there's nowhere to scroll
+        grid.resize(100.0, 200.0, (10.0, 20.0));
+        grid.scroll(1);
+        assert_eq!(visible(&grid), ["abcdef", "gh", "ij"]);
+    }
+
+    #[test]
+    fn oldest_lines_are_evicted() {
+        let mut grid = grid(10, 10, 2);
+        push(&mut grid, "a");
+        push(&mut grid, "b\nc");
+        assert_eq!(visible(&grid), ["b", "c"]);
+        push(&mut grid, "d");
+        assert_eq!(visible(&grid), ["c", "d"]);
+
+        let mut grid = self::grid(10, 10, 0);
+        push(&mut grid, "a");
+        push(&mut grid, "b");
+        assert_eq!(visible(&grid), ["b"]);
+    }
+}
@@ -0,0 +1,590 @@
+use std::ops::Range;
+
+use unicode_bidi::{BidiInfo, Level};
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::glyph_cache::{
+    subpixel_phase, FontSize, FontStyle, GlyphCache, GlyphKey,
+};
+use super::shaper::Direction;
+use super::{Decoration, Rgb, TextSpan};
+
+/// Where lines go horizontally when they're narrower than the layout
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Alignment {
+    #[default]
+    Left,
+    Center,
+    Right,
+    // spaces between words are stretched so lines fill the width, except
+    // for the last line of each paragraph
+    Justify,
+}
+
+/// Spans of text broken into lines and shaped, which is how `Renderer` draws
+/// text
+///
+/// right to left text is put in visual order by the Unicode bidi algorithm,
+/// with the paragraph direction taken from its first strong character.
+/// positions are in physical pixels, so text has to be laid out again when
+/// the scale factor changes
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    pub(super) lines: Vec<Line>,
+    // of the widest line, without trailing whitespace
+    width: f32,
+    height: f32,
+    // bytes of all the spans
+    len: usize,
+    scale_factor: f32,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Line {
+    pub glyphs: Vec<PositionedGlyph>,
+    pub spans: Vec<PositionedSpan>,
+    // from the top of the layout, rounded to whole pixels
+    pub top: f32,
+    pub height: f32,
+    pub baseline: f32,
+    // left to right
+    graphemes: Vec<Grapheme>,
+}
+
+// where a grapheme ended up on its line, for hit testing
+#[derive(Clone, Debug)]
+struct Grapheme {
+    offset: usize,
+    x: Range<f32>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(super) struct PositionedGlyph {
+    pub key: GlyphKey,
+    // origin from the left edge of the layout and up from the baseline. once
+    // on a line, x is in whole pixels with the rest in the key's phase
+    pub x: f32,
+    pub y: f32,
+    pub color: Rgb,
+}
+
+// the part of a span with a background or decorations on a line
+#[derive(Clone, Debug)]
+pub(super) struct PositionedSpan {
+    // from the left edge of the layout
+    pub x: Range<f32>,
+    // fills the height of the line
+    pub background: Option<Rgb>,
+    pub decoration: Decoration,
+    pub decoration_color: Rgb,
+    // of the span, decorations are placed by the metrics of its font
+    pub style: FontStyle,
+    pub size: FontSize,
+}
+
+// text between two break opportunities, the smallest piece a line can hold
+struct Word {
+    // in logical order
+    runs: Vec<Run>,
+    // without trailing whitespace, which hangs past the end of a line
+    width: f32,
+    spaces: Run,
+    // of the largest span in the word, sets the height of its line
+    size: FontSize,
+    // a hard line break or the end of the text follows
+    mandatory: bool,
+    paragraph_level: Level,
+}
+
+// text in one span and bidi level, shaped in its direction
+struct Run {
+    // x from the left edge of the run
+    glyphs: Vec<PositionedGlyph>,
+    graphemes: Vec<Grapheme>,
+    spans: Vec<PositionedSpan>,
+    advance: f32,
+    level: Level,
+}
+
+impl Word {
+    fn advance(&self) -> f32 {
+        self.width + self.spaces.advance
+    }
+}
+
+impl Run {
+    fn empty(level: Level) -> Self {
+        Self {
+            glyphs: Vec::new(),
+            graphemes: Vec::new(),
+            spans: Vec::new(),
+            advance: 0.0,
+            level,
+        }
+    }
+
+    // put `other` after this one, visually
+    fn append(&mut self, other: Run) {
+        let x = self.advance;
+        self.glyphs.extend(other.glyphs.into_iter().map(|glyph| {
+            PositionedGlyph {
+                x: glyph.x + x,
+                ..glyph
+            }
+        }));
+        self.graphemes
+            .extend(other.graphemes.into_iter().map(|g| g.offset_by(x)));
+        self.spans
+            .extend(other.spans.into_iter().map(|span| span.offset_by(x)));
+        self.advance += other.advance;
+    }
+}
+
+impl Grapheme {
+    fn offset_by(self, x: f32) -> Self {
+        Self {
+            offset: self.offset,
+            x: self.x.start + x..self.x.end + x,
+        }
+    }
+}
+
+impl PositionedGlyph {
+    // moved to `x` from the left edge of the layout, with the part of a pixel
+    // going into the glyph's subpixel phase
+    fn placed_at(self, x: f32) -> Self {
+        let (x, phase) = subpixel_phase(x);
+        Self {
+            key: GlyphKey { phase, ..self.key },
+            x,
+            ..self
+        }
+    }
+}
+
+impl PositionedSpan {
+    fn offset_by(self, x: f32) -> Self {
+        Self {
+            x: self.x.start + x..self.x.end + x,
+            ..self
+        }
+    }
+}
+
+impl Layout {
+    /// shape `spans` one after another and break them into lines no wider
+    /// than `max_width` physical pixels, or only at hard line breaks if it's
+    /// infinite
+    ///
+    /// lines break at Unicode line break opportunities. a word longer than
+    /// `max_width` gets a line to itself and overflows it
+    pub(super) fn new(
+        glyph_cache: &mut GlyphCache,
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+        scale_factor: f32,
+    ) -> Self {
+        let text: String = spans.iter().map(|span| span.text).collect();
+        let mut ranges = Vec::with_capacity(spans.len());
+        let mut start = 0;
+        for span in spans {
+            ranges.push((start..start + span.text.len(), span));
+            start += span.text.len();
+        }
+
+        // levels are resolved for whole paragraphs before they're broken
+        // into lines, then runs are reordered a line at a time
+        let bidi = BidiInfo::new(&text, None);
+
+        let mut words = Vec::new();
+        let mut start = 0;
+        for (end, opportunity) in linebreaks(&text) {
+            words.push(shape_word(
+                glyph_cache,
+                &text,
+                &ranges,
+                &bidi,
+                start..end,
+                opportunity == BreakOpportunity::Mandatory,
+                scale_factor,
+            ));
+            start = end;
+        }
+
+        // greedily fill lines, then align them once the widest is known
+        let mut lines: Vec<Vec<Word>> = Vec::new();
+        let mut current = Vec::new();
+        let mut pen_x = 0.0;
+        for word in words {
+            if !current.is_empty() && pen_x + word.width > max_width {
+                lines.push(std::mem::take(&mut current));
+                pen_x = 0.0;
+            }
+
+            pen_x += word.advance();
+            let mandatory = word.mandatory;
+            current.push(word);
+
+            if mandatory {
+                lines.push(std::mem::take(&mut current));
+                pen_x = 0.0;
+            }
+        }
+        if !current.is_empty() {
+            lines.push(current);
+        }
+
+        let widths: Vec<f32> =
+            lines.iter().map(|line| line_width(line)).collect();
+        let width = if max_width.is_finite() {
+            max_width
+        } else {
+            widths.iter().copied().fold(0.0, f32::max)
+        };
+
+        let mut top = 0.0;
+        let mut lines: Vec<_> = lines
+            .into_iter()
+            .zip(widths.iter())
+            .map(|(words, &line_width)| {
+                // lines always have at least one word
+                let size = words.iter().map(|word| word.size).max().unwrap();
+                let metrics = glyph_cache.metrics(FontStyle::REGULAR, size);
+                let line_top = top;
+                let line_height = metrics.line_height.round();
+                // line gap goes above the ascent, same as with cells
+                let baseline = top + line_height + metrics.descent.round();
+                top += line_height;
+
+                let slack = (width - line_width).max(0.0);
+                let paragraph_end = words.last().unwrap().mandatory;
+                let (mut pen_x, spacing) = match alignment {
+                    Alignment::Left => (0.0, 0.0),
+                    Alignment::Center => ((slack / 2.0).round(), 0.0),
+                    Alignment::Right => (slack, 0.0),
+                    Alignment::Justify if paragraph_end || words.len() < 2 => {
+                        (0.0, 0.0)
+                    }
+                    Alignment::Justify => {
+                        (0.0, slack / (words.len() - 1) as f32)
+                    }
+                };
+
+                let mut runs = Vec::new();
+                let last = words.len() - 1;
+                for (i, word) in words.into_iter().enumerate() {
+                    runs.extend(word.runs);
+                    let mut spaces = word.spaces;
+                    if i == last {
+                        // whitespace at the end of the line goes at the end
+                        // of the paragraph's direction, rule L1
+                        spaces.level = word.paragraph_level;
+                        // and isn't highlighted or decorated, since it hangs
+                        // off the line
+                        spaces.spans.clear();
+                    } else {
+                        spaces.advance += spacing;
+                    }
+                    runs.push(spaces);
+                }
+
+                let levels: Vec<_> = runs.iter().map(|run| run.level).collect();
+                let mut glyphs = Vec::new();
+                let mut graphemes = Vec::new();
+                let mut spans = Vec::new();
+                for i in BidiInfo::reorder_visual(&levels) {
+                    let run = &runs[i];
+                    glyphs.extend(
+                        run.glyphs
+                            .iter()
+                            .map(|glyph| glyph.placed_at(glyph.x + pen_x)),
+                    );
+                    graphemes.extend(
+                        run.graphemes
+                            .iter()
+                            .map(|g| g.clone().offset_by(pen_x)),
+                    );
+                    spans.extend(
+                        run.spans
+                            .iter()
+                            .map(|span| span.clone().offset_by(pen_x)),
+                    );
+                    pen_x += run.advance;
+                }
+                graphemes.sort_by(|a, b| a.x.start.total_cmp(&b.x.start));
+
+                Line {
+                    glyphs,
+                    spans,
+                    top: line_top,
+                    height: line_height,
+                    baseline,
+                    graphemes,
+                }
+            })
+            .collect();
+
+        let rtl = bidi
+            .paragraphs
+            .last()
+            .is_some_and(|paragraph| paragraph.level.is_rtl());
+        if let Some(line) = lines.last_mut() {
+            // past the end of the text, on whichever side that is
+            let edge = if rtl {
+                line.graphemes.first().map_or(0.0, |g| g.x.start)
+            } else {
+                line.graphemes.last().map_or(0.0, |g| g.x.end)
+            };
+            let end = Grapheme {
+                offset: text.len(),
+                x: if rtl {
+                    f32::NEG_INFINITY..edge
+                } else {
+                    edge..f32::INFINITY
+                },
+            };
+            if rtl {
+                line.graphemes.insert(0, end);
+            } else {
+                line.graphemes.push(end);
+            }
+        }
+
+        Self {
+            width: widths.iter().copied().fold(0.0, f32::max),
+            height: top,
+            lines,
+            len: text.len(),
+            scale_factor,
+        }
+    }
+
+    /// width, height and the baseline of the first line from the top, in
+    /// logical pixels
+    pub fn size(&self) -> (f32, f32, f32) {
+        let baseline = self.lines.first().map_or(0.0, |line| line.baseline);
+        (
+            self.width / self.scale_factor,
+            self.height / self.scale_factor,
+            baseline / self.scale_factor,
+        )
+    }
+
+    /// byte offset into the text of the grapheme at `point`, in logical
+    /// pixels from the top left corner of the layout, or of the closest one
+    /// on the closest line. points past the end of the last line are at the
+    /// length of the text
+    pub fn hit_test(&self, point: (f32, f32)) -> usize {
+        let (x, y) = (point.0 * self.scale_factor, point.1 * self.scale_factor);
+
+        let line = self
+            .lines
+            .iter()
+            .find(|line| y < line.top + line.height)
+            .or(self.lines.last());
+        let Some(line) = line else {
+            return self.len;
+        };
+
+        let distance = |grapheme: &&Grapheme| {
+            if grapheme.x.contains(&x) {
+                0.0
+            } else {
+                (grapheme.x.start - x).abs().min((grapheme.x.end - x).abs())
+            }
+        };
+        line.graphemes
+            .iter()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .map_or(self.len, |grapheme| grapheme.offset)
+    }
+}
+
+// the last word's trailing whitespace doesn't count
+fn line_width(words: &[Word]) -> f32 {
+    match words.split_last() {
+        Some((last, rest)) => {
+            rest.iter().map(Word::advance).sum::<f32>() + last.width
+        }
+        None => 0.0,
+    }
+}
+
+fn shape_word(
+    glyph_cache: &mut GlyphCache,
+    text: &str,
+    spans: &[(Range<usize>, &TextSpan)],
+    bidi: &BidiInfo,
+    word: Range<usize>,
+    mandatory: bool,
+    scale_factor: f32,
+) -> Word {
+    let levels = &bidi.levels;
+    let content_end = word.start + text[word.clone()].trim_end().len();
+
+    let mut runs = Vec::new();
+    let mut spaces = Run::empty(levels[content_end.min(word.end - 1)]);
+    let mut size = None;
+
+    for (range, span) in spans {
+        let start = range.start.max(word.start);
+        let end = range.end.min(word.end);
+        if start >= end {
+            continue;
+        }
+
+        let span_size = FontSize::new(span.size * scale_factor);
+        size = size.max(Some(span_size));
+
+        // split at every change of level
+        let mut run_start = start;
+        let content_end = end.min(content_end);
+        while run_start < content_end {
+            let level = levels[run_start];
+            let run_end = (run_start..content_end)
+                .find(|&i| levels[i] != level)
+                .unwrap_or(content_end);
+            runs.push(shape_run(
+                glyph_cache,
+                text,
+                run_start..run_end,
+                span,
+                span_size,
+                level,
+            ));
+            run_start = run_end;
+        }
+
+        // line breaks aren't drawn and take up no space
+        if end > content_end {
+            let start = start.max(content_end);
+            let end =
+                start + text[start..end].trim_end_matches(is_line_break).len();
+            let level = spaces.level;
+            spaces.append(shape_run(
+                glyph_cache,
+                text,
+                start..end,
+                span,
+                span_size,
+                level,
+            ));
+        }
+    }
+
+    let paragraph_level = bidi
+        .paragraphs
+        .iter()
+        .find(|paragraph| paragraph.range.contains(&word.start))
+        .map_or_else(Level::ltr, |paragraph| paragraph.level);
+
+    Word {
+        width: runs.iter().map(|run| run.advance).sum(),
+        runs,
+        spaces,
+        size: size.unwrap_or_else(|| {
+            FontSize::new(super::DEFAULT_FONT_SIZE * scale_factor)
+        }),
+        mandatory,
+        paragraph_level,
+    }
+}
+
+// shape `range` of `text` in the direction of `level`
+fn shape_run(
+    glyph_cache: &mut GlyphCache,
+    text: &str,
+    range: Range<usize>,
+    span: &TextSpan,
+    size: FontSize,
+    level: Level,
+) -> Run {
+    let direction = if level.is_rtl() {
+        Direction::RightToLeft
+    } else {
+        Direction::LeftToRight
+    };
+    let offset = range.start;
+    let text = &text[range];
+    let shaped = glyph_cache.shape(text, span.style, size, direction);
+
+    // a glyph stands for the characters from its cluster up to the next
+    // one, which may be several graphemes with ligatures. their share of
+    // its advance is split evenly
+    let mut clusters: Vec<_> =
+        shaped.iter().map(|glyph| glyph.cluster).collect();
+    clusters.sort_unstable();
+    clusters.dedup();
+    let starts: Vec<_> = text.grapheme_indices(true).map(|(i, _)| i).collect();
+    let mut extents = vec![f32::INFINITY..f32::NEG_INFINITY; starts.len()];
+
+    let mut glyphs = Vec::with_capacity(shaped.len());
+    let mut pen_x = 0.0;
+    for glyph in &shaped {
+        glyphs.push(PositionedGlyph {
+            key: glyph.key,
+            x: pen_x + glyph.x_offset,
+            y: glyph.y_offset,
+            color: span.color,
+        });
+
+        let next = clusters
+            .iter()
+            .find(|&&cluster| cluster > glyph.cluster)
+            .map_or(text.len(), |&cluster| cluster);
+        let first = starts.partition_point(|&start| start <= glyph.cluster) - 1;
+        let last =
+            (starts.partition_point(|&start| start < next) - 1).max(first);
+        let share = glyph.x_advance / (last - first + 1) as f32;
+        for (i, extent) in extents[first..=last].iter_mut().enumerate() {
+            // right to left graphemes start on the right of the glyph
+            let i = if level.is_rtl() { last - first - i } else { i };
+            let x = pen_x + i as f32 * share;
+            extent.start = extent.start.min(x);
+            extent.end = extent.end.max(x + share);
+        }
+
+        pen_x += glyph.x_advance;
+    }
+
+    let graphemes = starts
+        .into_iter()
+        .zip(extents)
+        // a grapheme without a glyph, which shouldn't happen
+        .filter(|(_, x)| x.start <= x.end)
+        .map(|(start, x)| Grapheme {
+            offset: offset + start,
+            x,
+        })
+        .collect();
+
+    let spans = if span.decoration.is_empty() && span.background.is_none() {
+        Vec::new()
+    } else {
+        vec![PositionedSpan {
+            x: 0.0..pen_x,
+            background: span.background,
+            decoration: span.decoration,
+            decoration_color: span.decoration_color.unwrap_or(span.color),
+            style: span.style,
+            size,
+        }]
+    };
+
+    Run {
+        glyphs,
+        graphemes,
+        spans,
+        advance: pen_x,
+        level,
+    }
+}
+
+fn is_line_break(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\x0b' | '\x0c' | '\u{85}' | '\u{2028}' | '\u{2029}'
+    )
+}
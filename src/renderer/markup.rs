@@ -0,0 +1,389 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use super::{Decoration, FontStyle, Rgb, TextSpan};
+
+/// Everything about how a run of text is drawn, apart from the text
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpanStyle {
+    pub font: FontStyle,
+    // in logical pixels
+    pub size: f32,
+    pub color: Rgb,
+    pub background: Option<Rgb>,
+    pub decoration: Decoration,
+    // defaults to `color`
+    pub decoration_color: Option<Rgb>,
+}
+
+impl SpanStyle {
+    pub fn new(size: f32, color: Rgb) -> Self {
+        Self {
+            font: FontStyle::REGULAR,
+            size,
+            color,
+            background: None,
+            decoration: Decoration::empty(),
+            decoration_color: None,
+        }
+    }
+
+    pub fn span(self, text: &str) -> TextSpan<'_> {
+        TextSpan {
+            text,
+            style: self.font,
+            size: self.size,
+            color: self.color,
+            background: self.background,
+            decoration: self.decoration,
+            decoration_color: self.decoration_color,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MarkupError {
+    // a `[` without a `]`
+    UnterminatedTag,
+    UnknownTag(String),
+    InvalidValue { tag: String, value: String },
+    // a closing tag for a different tag than the innermost open one
+    UnexpectedClose(String),
+    // tags still open at the end
+    Unclosed(String),
+}
+
+impl Error for MarkupError {}
+
+impl fmt::Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self {
+            MarkupError::UnterminatedTag => write!(f, "Tag is missing a `]`"),
+            MarkupError::UnknownTag(tag) => write!(f, "Unknown tag `{}`", tag),
+            MarkupError::InvalidValue { tag, value } => {
+                write!(f, "Invalid value `{}` for tag `{}`", value, tag)
+            }
+            MarkupError::UnexpectedClose(tag) => {
+                write!(f, "Closing tag `{}` doesn't match an open tag", tag)
+            }
+            MarkupError::Unclosed(tag) => {
+                write!(f, "Tag `{}` isn't closed", tag)
+            }
+        }
+    }
+}
+
+/// Text made up of runs in different styles, usually parsed from markup
+///
+/// tags in square brackets change the style until the matching closing tag,
+/// and nest:
+///
+/// - `[b]`, `[i]` bold and italic
+/// - `[color=#f80]`, `[bg=#203040]` text and background color, as 3 or 6
+///   hex digits
+/// - `[size=24]` size in logical pixels
+/// - `[u]`, `[uu]`, `[curl]`, `[s]`, `[o]` underline, double underline,
+///   undercurl, strikethrough and overline, optionally in another color like
+///   `[curl=#f00]`
+///
+/// closing tags are the name after a slash, `[/color]`. `[[` is a literal
+/// `[`
+#[derive(Clone, Debug, Default)]
+pub struct RichText {
+    text: String,
+    // consecutive, covering all of `text`
+    runs: Vec<(Range<usize>, SpanStyle)>,
+}
+
+impl RichText {
+    /// parse `markup`, with text outside any tags in `base`
+    pub fn parse(markup: &str, base: SpanStyle) -> Result<Self, MarkupError> {
+        let mut rich_text = Self::default();
+        // open tags along with the style from before each
+        let mut open: Vec<(&str, SpanStyle)> = Vec::new();
+        let mut style = base;
+        let mut rest = markup;
+
+        while let Some(i) = rest.find('[') {
+            rich_text.push(&rest[..i], style);
+            rest = &rest[i..];
+
+            if let Some(after) = rest.strip_prefix("[[") {
+                rich_text.push("[", style);
+                rest = after;
+                continue;
+            }
+
+            let end = rest.find(']').ok_or(MarkupError::UnterminatedTag)?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+
+            if let Some(name) = tag.strip_prefix('/') {
+                match open.pop() {
+                    Some((open_name, before)) if open_name == name => {
+                        style = before
+                    }
+                    _ => return Err(MarkupError::UnexpectedClose(name.into())),
+                }
+            } else {
+                let (name, value) = match tag.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (tag, None),
+                };
+                open.push((name, style));
+                style = apply_tag(style, name, value)?;
+            }
+        }
+        rich_text.push(rest, style);
+
+        match open.pop() {
+            Some((name, _)) => Err(MarkupError::Unclosed(name.into())),
+            None => Ok(rich_text),
+        }
+    }
+
+    /// a span for every run, for `Renderer::layout` and `draw_spans`
+    pub fn spans(&self) -> Vec<TextSpan<'_>> {
+        self.runs
+            .iter()
+            .map(|(range, style)| style.span(&self.text[range.clone()]))
+            .collect()
+    }
+
+    // append `text`, continuing the last run if it's in the same style
+    fn push(&mut self, text: &str, style: SpanStyle) {
+        if text.is_empty() {
+            return;
+        }
+
+        let start = self.text.len();
+        self.text.push_str(text);
+        let end = self.text.len();
+        match self.runs.last_mut() {
+            Some((range, last)) if *last == style => range.end = end,
+            _ => self.runs.push((start..end, style)),
+        }
+    }
+}
+
+/// `text` with tags escaped, so it shows up as is when it's part of markup
+pub fn escape_markup(text: &str) -> String {
+    text.replace('[', "[[")
+}
+
+// the style inside tag `name`, with an optional `value` after an `=`
+fn apply_tag(
+    style: SpanStyle,
+    name: &str,
+    value: Option<&str>,
+) -> Result<SpanStyle, MarkupError> {
+    let invalid = || MarkupError::InvalidValue {
+        tag: name.into(),
+        value: value.unwrap_or_default().into(),
+    };
+    let color = || value.and_then(parse_color).ok_or_else(invalid);
+    // decorations take an optional color
+    let decoration_color = || match value {
+        Some(value) => parse_color(value).map(Some).ok_or_else(invalid),
+        None => Ok(style.decoration_color),
+    };
+    let decorate = |decoration| {
+        Ok(SpanStyle {
+            decoration: style.decoration | decoration,
+            decoration_color: decoration_color()?,
+            ..style
+        })
+    };
+
+    match name {
+        "b" => Ok(SpanStyle {
+            font: FontStyle {
+                bold: true,
+                ..style.font
+            },
+            ..style
+        }),
+        "i" => Ok(SpanStyle {
+            font: FontStyle {
+                italic: true,
+                ..style.font
+            },
+            ..style
+        }),
+        "color" => Ok(SpanStyle {
+            color: color()?,
+            ..style
+        }),
+        "bg" => Ok(SpanStyle {
+            background: Some(color()?),
+            ..style
+        }),
+        "size" => {
+            let size = value
+                .and_then(|value| value.parse().ok())
+                .filter(|&size: &f32| size > 0.0 && size.is_finite())
+                .ok_or_else(invalid)?;
+            Ok(SpanStyle { size, ..style })
+        }
+        "u" => decorate(Decoration::UNDERLINE),
+        "uu" => decorate(Decoration::DOUBLE_UNDERLINE),
+        "curl" => decorate(Decoration::UNDERCURL),
+        "s" => decorate(Decoration::STRIKETHROUGH),
+        "o" => decorate(Decoration::OVERLINE),
+        _ => Err(MarkupError::UnknownTag(name.into())),
+    }
+}
+
+// `#rgb` or `#rrggbb`
+fn parse_color(value: &str) -> Option<Rgb> {
+    let hex = value.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |i: usize, len: usize| {
+        let value =
+            u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).ok()?;
+        // a single digit is repeated, f is ff
+        Some(if len == 1 { value * 17 } else { value })
+    };
+    let len = match hex.len() {
+        3 => 1,
+        6 => 2,
+        _ => return None,
+    };
+    Some(Rgb::new(
+        channel(0, len)?,
+        channel(1, len)?,
+        channel(2, len)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb = Rgb::new(255, 255, 255);
+
+    fn base() -> SpanStyle {
+        SpanStyle::new(16.0, WHITE)
+    }
+
+    // every run's text with its style
+    fn runs(markup: &str) -> Vec<(String, SpanStyle)> {
+        let rich_text = RichText::parse(markup, base()).unwrap();
+        rich_text
+            .runs
+            .iter()
+            .map(|(range, style)| {
+                (rich_text.text[range.clone()].into(), *style)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nesting() {
+        let bold = SpanStyle {
+            font: FontStyle {
+                bold: true,
+                ..base().font
+            },
+            ..base()
+        };
+        let bold_italic = SpanStyle {
+            font: FontStyle {
+                italic: true,
+                ..bold.font
+            },
+            ..bold
+        };
+        assert_eq!(
+            runs("a[b]b[i]c[/i]d[/b]e"),
+            [
+                ("a".into(), base()),
+                ("b".into(), bold),
+                ("c".into(), bold_italic),
+                ("d".into(), bold),
+                ("e".into(), base()),
+            ]
+        );
+    }
+
+    #[test]
+    fn adjacent_runs_in_the_same_style_are_merged() {
+        assert_eq!(runs("a[b][/b]b[[c"), [("ab[c".into(), base())]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(runs("[[b]"), [("[b]".into(), base())]);
+        assert_eq!(runs("a[[[[b"), [("a[[b".into(), base())]);
+        assert_eq!(runs("]"), [("]".into(), base())]);
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#f80"), Some(Rgb::new(0xff, 0x88, 0x00)));
+        assert_eq!(parse_color("#203040"), Some(Rgb::new(0x20, 0x30, 0x40)));
+        assert_eq!(parse_color("f80"), None);
+        assert_eq!(parse_color("#f8"), None);
+        assert_eq!(parse_color("#ff880"), None);
+        assert_eq!(parse_color("#ggg"), None);
+        // multibyte characters must not be split
+        assert_eq!(parse_color("#é0"), None);
+
+        let style = runs("[color=#f80][bg=#203040]x[/bg][/color]")[0].1;
+        assert_eq!(style.color, Rgb::new(0xff, 0x88, 0x00));
+        assert_eq!(style.background, Some(Rgb::new(0x20, 0x30, 0x40)));
+    }
+
+    #[test]
+    fn decorations() {
+        let style = runs("[u][curl=#f00]x[/curl][/u]")[0].1;
+        assert_eq!(
+            style.decoration,
+            Decoration::UNDERLINE | Decoration::UNDERCURL
+        );
+        assert_eq!(style.decoration_color, Some(Rgb::new(255, 0, 0)));
+    }
+
+    #[test]
+    fn errors() {
+        let parse = |markup| RichText::parse(markup, base()).unwrap_err();
+        assert!(matches!(
+            parse("[b]x[/i]"),
+            MarkupError::UnexpectedClose(tag) if tag == "i"
+        ));
+        assert!(matches!(
+            parse("x[/b]"),
+            MarkupError::UnexpectedClose(tag) if tag == "b"
+        ));
+        assert!(matches!(
+            parse("[b][i]x[/i]"),
+            MarkupError::Unclosed(tag) if tag == "b"
+        ));
+        assert!(matches!(parse("[b"), MarkupError::UnterminatedTag));
+        assert!(matches!(
+            parse("[blink]x[/blink]"),
+            MarkupError::UnknownTag(tag) if tag == "blink"
+        ));
+        assert!(matches!(
+            parse("[color=red]x[/color]"),
+            MarkupError::InvalidValue { tag, value }
+                if tag == "color" && value == "red"
+        ));
+        assert!(matches!(
+            parse("[size=-1]x[/size]"),
+            MarkupError::InvalidValue { .. }
+        ));
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        for text in ["", "plain", "[b]not bold[/b]", "[[", "a[", "[", "]["] {
+            let escaped = escape_markup(text);
+            assert_eq!(RichText::parse(&escaped, base()).unwrap().text, text);
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::ffi::CString;
-use std::mem::{size_of, size_of_val};
+use std::io;
+use std::mem::{self, size_of};
+use std::ops::Range;
 use std::ptr;
 use std::sync::atomic::{AtomicBool, Ordering};
 
@@ -12,17 +14,48 @@ use glutin::{
 };
 
 mod atlas;
+mod builtin;
+mod damage;
+mod decoration;
+mod disk_cache;
 mod glyph_cache;
+mod grid;
+mod layout;
+mod markup;
+mod raster;
+mod sdf;
 mod shader;
+mod shaper;
+mod text_cache;
 
-use glyph_cache::GlyphCache;
+use decoration::DecorationLine;
+use glyph_cache::{
+    subpixel_phase, FontSize, Glyph, GlyphCache, GlyphKey, LineMetrics,
+    SUBPIXEL_PHASES,
+};
+use sdf::SDF_RANGE;
+use shaper::{Direction, ShapedGlyph};
+use text_cache::{RecordedBatch, TextCache, TextKey};
+
+pub use damage::{DamageRect, DamageTracker};
+pub use decoration::Decoration;
+pub use glyph_cache::{Antialiasing, FontStyle, Hinting, RasterOptions};
+pub use grid::{Cell, CellGrid};
+pub use layout::{Alignment, Layout};
+pub use markup::{escape_markup, MarkupError, RichText, SpanStyle};
 use shader::{ShaderError, ShaderProgram};
 
 bitflags! {
     #[repr(C)]
     struct RenderingGlyphFlags: u8 {
-        const COLORED   = 0b0000_0001;
-        const WIDE_CHAR = 0b0000_0010;
+        const COLORED    = 0b0000_0001;
+        const WIDE_CHAR  = 0b0000_0010;
+        // a solid quad instead of a glyph
+        const DECORATION = 0b0000_0100;
+        // a wave instead of a solid quad
+        const CURLY      = 0b0000_1000;
+        // a background filling the quad instead of the cell
+        const FILL       = 0b0001_0000;
     }
 }
 
@@ -38,22 +71,74 @@ macro_rules! cstr {
     };
 }
 
-static SHADER_F: &str = include_str!("../../res/test.f.glsl");
-static SHADER_V: &str = include_str!("../../res/test.v.glsl");
+static TEXT_SHADER_F: &str = include_str!("../../res/text.f.glsl");
+static TEXT_SHADER_V: &str = include_str!("../../res/text.v.glsl");
+static SDF_SHADER_F: &str = include_str!("../../res/sdf.f.glsl");
 
 const BATCH_MAX: usize = 0x1_0000;
-type Vertex = [f32; 5];
-const VERTICES: [Vertex; 6] = [
-    [-1.0, -1.0, 0.0, 0.0, 1.0],
-    [-1.0, 1.0, 0.0, 0.0, 0.0],
-    [1.0, 1.0, 0.0, 1.0, 0.0],
-    [-1.0, -1.0, 0.0, 0.0, 1.0],
-    [1.0, 1.0, 0.0, 1.0, 0.0],
-    [1.0, -1.0, 0.0, 1.0, 1.0],
-];
+// used by `draw_text`, in logical pixels
+pub const DEFAULT_FONT_SIZE: f32 = 32.0;
+// distance fields are generated at this size in pixels and scaled from there
+const SDF_FONT_SIZE: f32 = 48.0;
 static GL_FUNS_LOADED: AtomicBool = AtomicBool::new(false);
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// A piece of text drawn in a single style, size and color
+#[derive(Copy, Clone, Debug)]
+pub struct TextSpan<'a> {
+    pub text: &'a str,
+    pub style: FontStyle,
+    // in logical pixels
+    pub size: f32,
+    pub color: Rgb,
+    // fills the height of the line behind the text
+    pub background: Option<Rgb>,
+    pub decoration: Decoration,
+    // defaults to `color`
+    pub decoration_color: Option<Rgb>,
+}
+
+impl<'a> TextSpan<'a> {
+    pub fn new(text: &'a str, style: FontStyle, size: f32, color: Rgb) -> Self {
+        Self {
+            text,
+            style,
+            size,
+            color,
+            background: None,
+            decoration: Decoration::empty(),
+            decoration_color: None,
+        }
+    }
+}
+
+/// Look of text drawn by `Renderer::draw_sdf_text`
+///
+/// widths are in logical pixels and 0 disables the effect. together they
+/// can't reach further out than `SDF_RANGE` scaled to the text size
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SdfStyle {
+    pub color: Rgb,
+    pub outline_width: f32,
+    pub outline_color: Rgb,
+    // fades out over this distance outside the outline
+    pub glow_width: f32,
+    pub glow_color: Rgb,
+}
+
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 struct InstanceData {
     // coords
@@ -68,12 +153,15 @@ struct InstanceData {
     width: i16,
     height: i16,
 
-    // uv info
+    // uv info, in pixels
     uv_left: f32,
     uv_bot: f32,
     uv_width: f32,
     uv_height: f32,
 
+    // texture array layer
+    layer: u16,
+
     // foreground
     r: u8,
     g: u8,
@@ -118,57 +206,225 @@ impl TextRenderBatch {
         self.instances.clear();
     }
 
-    pub fn render(&mut self) {}
-
-    // pub fn add_item(&mut self, glyph: &Glyph) {
-    //     if !self.is_empty() && self.tex != glyph.tex_id {
-    //         self.render();
-    //         self.tex = glyph.tex_id;
-    //     }
-
-    //     let mut cell_flags = RenderingGlyphFlags::empty();
-
-    //     let instance = InstanceData {
-    //         col: 0,
-    //         row: 0,
-    //         left: glyph.left,
-    //         top: glyph.top,
-    //         width: glyph.width,
-    //         height: glyph.height,
-    //         uv_left: glyph.uv_left,
-    //         uv_bot: glyph.uv_bot,
-    //         uv_width: glyph.uv_width,
-    //         uv_height: glyph.uv_height,
-    //         r: 255,
-    //         g: 255,
-    //         b: 255,
-    //         cell_flags,
-    //         bg_r: 0,
-    //         bg_g: 0,
-    // bg_b: 0,
-    // bg_a: 0,
-    // };
-
-    // self.instances.push(instance);
-
-    // if self.full() {
-    // self.render();
-    // }
-    // }
+    /// whether adding a glyph from `tex` requires rendering the batch first
+    #[inline]
+    pub fn needs_flush(&self, tex: GLuint) -> bool {
+        self.full() || (!self.is_empty() && self.tex != tex)
+    }
+
+    /// draw all instances in the batch and empty it
+    ///
+    /// expects the text program, vao and instance vbo to be bound
+    pub unsafe fn render(&mut self, program: &TextShaderProgram) {
+        if self.is_empty() {
+            return;
+        }
+
+        gl::BufferSubData(
+            gl::ARRAY_BUFFER,
+            0,
+            (self.instances.len() * size_of::<InstanceData>()) as isize,
+            self.instances.as_ptr().cast(),
+        );
+
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.tex);
+
+        let count = self.instances.len() as GLsizei;
+        for pass in [RenderingPass::Background, RenderingPass::Foreground] {
+            program.set_rendering_pass(pass);
+            gl::DrawElementsInstanced(
+                gl::TRIANGLES,
+                6,
+                gl::UNSIGNED_BYTE,
+                ptr::null(),
+                count,
+            );
+        }
+
+        self.clear();
+    }
+
+    /// add instances recorded from an earlier batch for `tex`, which have to
+    /// fit
+    pub fn add_instances(&mut self, tex: GLuint, instances: &[InstanceData]) {
+        self.tex = tex;
+        self.instances.extend_from_slice(instances);
+    }
+
+    pub fn add_item(&mut self, col: u16, row: u16, glyph: &Glyph, color: Rgb) {
+        self.tex = glyph.tex_id;
+
+        let mut cell_flags = RenderingGlyphFlags::empty();
+        cell_flags.set(RenderingGlyphFlags::COLORED, glyph.colored);
+
+        let instance = InstanceData {
+            col,
+            row,
+            left: glyph.left,
+            top: glyph.top,
+            width: glyph.width,
+            height: glyph.height,
+            uv_left: glyph.uv_left,
+            uv_bot: glyph.uv_bot,
+            uv_width: glyph.uv_width,
+            uv_height: glyph.uv_height,
+            layer: glyph.layer,
+            r: color.r,
+            g: color.g,
+            b: color.b,
+            cell_flags,
+            bg_r: 0,
+            bg_g: 0,
+            bg_b: 0,
+            bg_a: 0,
+        };
+
+        self.instances.push(instance);
+    }
+
+    /// draw a decoration line `width` pixels wide, with its left edge `left`
+    /// pixels from the left of the cell. it doesn't sample the atlas, so the
+    /// batch doesn't have to be for any texture in particular
+    pub fn add_decoration(
+        &mut self,
+        col: u16,
+        row: u16,
+        left: i16,
+        width: i16,
+        line: &DecorationLine,
+        color: Rgb,
+    ) {
+        let mut cell_flags = RenderingGlyphFlags::DECORATION;
+        cell_flags.set(RenderingGlyphFlags::CURLY, line.curly);
+
+        let instance = InstanceData {
+            col,
+            row,
+            left,
+            top: line.top,
+            width,
+            height: line.height,
+            // the shader needs the thickness of waves
+            uv_left: line.thickness,
+            uv_bot: 0.,
+            uv_width: 0.,
+            uv_height: 0.,
+            layer: 0,
+            r: color.r,
+            g: color.g,
+            b: color.b,
+            cell_flags,
+            bg_r: 0,
+            bg_g: 0,
+            bg_b: 0,
+            bg_a: 0,
+        };
+
+        self.instances.push(instance);
+    }
+
+    /// fill a rectangle of `size` pixels behind any glyphs, with its top
+    /// left corner at `position` from `cell` like a glyph's left and top
+    pub fn add_fill(
+        &mut self,
+        cell: (u16, u16),
+        position: (i16, i16),
+        size: (i16, i16),
+        color: Rgb,
+    ) {
+        // nothing is drawn in the foreground pass
+        let instance = InstanceData {
+            col: cell.0,
+            row: cell.1,
+            left: position.0,
+            top: position.1,
+            width: size.0,
+            height: size.1,
+            uv_left: 0.,
+            uv_bot: 0.,
+            uv_width: 0.,
+            uv_height: 0.,
+            layer: 0,
+            r: 0,
+            g: 0,
+            b: 0,
+            cell_flags: RenderingGlyphFlags::FILL,
+            bg_r: color.r,
+            bg_g: color.g,
+            bg_b: color.b,
+            bg_a: 255,
+        };
+
+        self.instances.push(instance);
+    }
+
+    /// fill a cell, or two if it's `wide`, with `color` behind any glyphs
+    pub fn add_background(
+        &mut self,
+        col: u16,
+        row: u16,
+        color: Rgb,
+        wide: bool,
+    ) {
+        let mut cell_flags = RenderingGlyphFlags::empty();
+        cell_flags.set(RenderingGlyphFlags::WIDE_CHAR, wide);
+
+        // no glyph, so nothing is drawn in the foreground pass
+        let instance = InstanceData {
+            col,
+            row,
+            left: 0,
+            top: 0,
+            width: 0,
+            height: 0,
+            uv_left: 0.,
+            uv_bot: 0.,
+            uv_width: 0.,
+            uv_height: 0.,
+            layer: 0,
+            r: 0,
+            g: 0,
+            b: 0,
+            cell_flags,
+            bg_r: color.r,
+            bg_g: color.g,
+            bg_b: color.b,
+            bg_a: 255,
+        };
+
+        self.instances.push(instance);
+    }
 }
 
 pub struct Renderer {
-    program: ShaderProgram,
+    program: TextShaderProgram,
+    sdf_program: SdfShaderProgram,
     vao: GLuint,
-    //    ebo: GLuint,
-    vbo: GLuint,
+    ebo: GLuint,
+    vbo_instance: GLuint,
     glyph_cache: GlyphCache,
-    //    text_batch: TextRenderBatch,
+    text_batch: TextRenderBatch,
+    text_cache: TextCache,
+    // batches end up here instead of being drawn while it's set, see
+    // `record_layout`
+    recording: Option<Vec<RecordedBatch>>,
+    // glyphs queued while recording
+    recorded_glyphs: Vec<GlyphKey>,
+    // pixel to clip space scale, set on resize
+    projection_scale: (f32, f32),
+    // in physical pixels, scissor rects are from the bottom
+    height: f32,
+    // physical pixels per logical pixel
+    scale_factor: f32,
 }
 
 impl Renderer {
     pub fn new(
         context: &PossiblyCurrentContext,
+        options: RasterOptions,
+        texture_array: bool,
+        scale_factor: f32,
     ) -> Result<Self, Box<dyn Error>> {
         if !GL_FUNS_LOADED.swap(true, Ordering::Relaxed) {
             let gl_display = context.display();
@@ -178,43 +434,40 @@ impl Renderer {
             });
         };
 
-        // unsafe {
-        // let version = CStr::from_ptr(gl::GetString(gl::VERSION) as *const i8).to_str()?;
-        // println!("{}", version);
-        // }
-
-        let program = ShaderProgram::new(SHADER_V, SHADER_F)?;
+        let program = TextShaderProgram::new()?;
+        let sdf_program = SdfShaderProgram::new()?;
         let mut vao: GLuint = 0;
-        //let mut ebo: GLuint = 0;
-        let mut vbo: GLuint = 0;
+        let mut ebo: GLuint = 0;
+        let mut vbo_instance: GLuint = 0;
 
         unsafe {
             gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            set_blend_func(options.antialiasing);
 
             gl::DepthMask(gl::FALSE);
 
             gl::GenVertexArrays(1, &mut vao);
-            //gl::GenBuffers(1, &mut ebo);
-            gl::GenBuffers(1, &mut vbo);
+            gl::GenBuffers(1, &mut ebo);
+            gl::GenBuffers(1, &mut vbo_instance);
             gl::BindVertexArray(vao);
 
+            // the quad corners themselves come from gl_VertexID
             let indices: [u8; 6] = [0, 1, 3, 1, 2, 3];
 
-            // gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
-            // gl::BufferData(
-            //     gl::ELEMENT_ARRAY_BUFFER,
-            //     (6 * size_of::<u8>()) as isize,
-            //     indices.as_ptr() as *const _,
-            //     gl::STATIC_DRAW,
-            // );
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
+            gl::BufferData(
+                gl::ELEMENT_ARRAY_BUFFER,
+                (6 * size_of::<u8>()) as isize,
+                indices.as_ptr() as *const _,
+                gl::STATIC_DRAW,
+            );
 
-            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo_instance);
             gl::BufferData(
                 gl::ARRAY_BUFFER,
-                size_of_val(&VERTICES) as isize,
-                VERTICES.as_ptr().cast(),
-                gl::STATIC_DRAW,
+                (BATCH_MAX * size_of::<InstanceData>()) as isize,
+                ptr::null(),
+                gl::STREAM_DRAW,
             );
 
             gl::ClearColor(0.0, 0.0, 0.0, 1.0);
@@ -230,11 +483,11 @@ impl Renderer {
                         $count,
                         $gl_type,
                         gl::FALSE,
-                        size_of::<Vertex>() as i32,
+                        size_of::<InstanceData>() as i32,
                         size as *const _,
                     );
                     gl::EnableVertexAttribArray(index);
-                    //gl::VertexAttribDivisor(index, 1);
+                    gl::VertexAttribDivisor(index, 1);
 
                     #[allow(unused_assignments)]
                     {
@@ -244,65 +497,129 @@ impl Renderer {
                 };
             }
 
-            add_attr!(3, gl::FLOAT, f32);
-            add_attr!(2, gl::FLOAT, f32);
-            // add_attr!(2, gl::FLOAT, f32);
-
             // coords
-            // add_attr!(2, gl::UNSIGNED_SHORT, u16);
+            add_attr!(2, gl::UNSIGNED_SHORT, u16);
 
             // glyph offset and size
-            // add_attr!(4, gl::SHORT, i16);
+            add_attr!(4, gl::SHORT, i16);
 
             // uv info
-            // add_attr!(4, gl::FLOAT, f32);
+            add_attr!(4, gl::FLOAT, f32);
 
-            // color
-            // add_attr!(3, gl::UNSIGNED_BYTE, u8);
+            // texture array layer
+            add_attr!(1, gl::UNSIGNED_SHORT, u16);
+
+            // color and cell flags
+            add_attr!(4, gl::UNSIGNED_BYTE, u8);
 
             // background color.
-            // add_attr!(4, gl::UNSIGNED_BYTE, u8);
+            add_attr!(4, gl::UNSIGNED_BYTE, u8);
 
             // clean up
             gl::BindVertexArray(0);
             gl::BindBuffer(gl::ARRAY_BUFFER, 0);
-            // gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
         }
 
         let text_batch = TextRenderBatch::new();
 
-        let mut glyph_cache = GlyphCache::new(32.0)?;
+        let mut glyph_cache = GlyphCache::new(options, texture_array)?;
 
-        glyph_cache.cache_common();
+        glyph_cache
+            .cache_common(FontSize::new(DEFAULT_FONT_SIZE * scale_factor));
 
         Ok(Self {
             program,
+            sdf_program,
             vao,
-            //ebo,
-            vbo,
+            ebo,
+            vbo_instance,
             glyph_cache,
-            //text_batch,
+            text_batch,
+            text_cache: TextCache::default(),
+            recording: None,
+            recorded_glyphs: Vec::new(),
+            projection_scale: (0.0, 0.0),
+            height: 0.0,
+            scale_factor,
         })
     }
 
+    /// glyphs are rasterized for the new scale factor as they're drawn, so
+    /// everything cached for the old one is thrown away
+    pub fn set_scale_factor(&mut self, scale_factor: f32) {
+        if scale_factor == self.scale_factor {
+            return;
+        }
+
+        self.scale_factor = scale_factor;
+        self.glyph_cache.clear();
+        self.glyph_cache
+            .cache_common(FontSize::new(DEFAULT_FONT_SIZE * scale_factor));
+    }
+
+    /// glyphs are rasterized again with the new options as they're drawn
+    pub fn set_raster_options(&mut self, options: RasterOptions) {
+        if !self.glyph_cache.set_raster_options(options) {
+            return;
+        }
+
+        unsafe {
+            set_blend_func(options.antialiasing);
+        }
+        let size = FontSize::new(DEFAULT_FONT_SIZE * self.scale_factor);
+        self.glyph_cache.cache_common(size);
+    }
+
+    /// keep the glyphs rasterized so far for the next run, see
+    /// `GlyphCache::save`
+    pub fn save_glyph_cache(&self) -> io::Result<()> {
+        let size = FontSize::new(DEFAULT_FONT_SIZE * self.scale_factor);
+        self.glyph_cache.save(size)
+    }
+
+    pub fn scale_factor(&self) -> f32 {
+        self.scale_factor
+    }
+
+    /// takes the size in physical pixels
     pub fn resize(&mut self, width: f32, height: f32) {
-        let scale_x = 2. / width;
-        let scale_y = -2. / height;
-        let offset_x = -1.;
-        let offset_y = 1.;
+        self.projection_scale = (2. / width, -2. / height);
+        self.height = height;
 
         unsafe {
             gl::Viewport(0, 0, width as i32, height as i32);
-            gl::UseProgram(self.program.id());
+        }
+    }
+
+    /// limit on the texture memory used for glyphs, in bytes
+    pub fn set_atlas_budget(&mut self, bytes: usize) {
+        self.glyph_cache.set_atlas_budget(bytes);
+    }
+
+    /// call after drawing each frame, glyphs that haven't been drawn in a
+    /// while may be evicted here
+    pub fn end_frame(&mut self) {
+        self.glyph_cache.end_frame();
+        self.text_cache.end_frame();
+    }
 
-            // gl::Uniform4f(
-            //     self.program.u_projection,
-            //     offset_x,
-            //     offset_y,
-            //     scale_x,
-            //     scale_y,
-            // );
-            gl::UseProgram(0);
+    /// only draw and clear inside `rect` until this is called again, or
+    /// everywhere for `None`
+    pub fn set_scissor(&self, rect: Option<DamageRect>) {
+        unsafe {
+            match rect {
+                Some(rect) => {
+                    gl::Enable(gl::SCISSOR_TEST);
+                    gl::Scissor(
+                        rect.x,
+                        self.height as i32 - rect.y - rect.height,
+                        rect.width,
+                        rect.height,
+                    );
+                }
+                None => gl::Disable(gl::SCISSOR_TEST),
+            }
         }
     }
 
@@ -312,18 +629,618 @@ impl Renderer {
         }
     }
 
-    pub fn draw(&self) {
+    /// height of a line drawn by `draw_text`, in logical pixels
+    pub fn line_height(&self) -> f32 {
+        let size = FontSize::new(DEFAULT_FONT_SIZE * self.scale_factor);
+        let metrics = self.glyph_cache.metrics(FontStyle::REGULAR, size);
+        metrics.line_height / self.scale_factor
+    }
+
+    /// width of a cell drawn by `draw_cells`, in logical pixels
+    pub fn cell_width(&self) -> f32 {
+        let size = FontSize::new(DEFAULT_FONT_SIZE * self.scale_factor);
+        let metrics = self.glyph_cache.metrics(FontStyle::REGULAR, size);
+        metrics.cell_width.round() / self.scale_factor
+    }
+
+    /// size of a cell on a `CellGrid` with text at `font_size`, taken from
+    /// the monospace font. all in logical pixels
+    pub fn grid_cell_size(&self, font_size: f32) -> (f32, f32) {
+        let size = FontSize::new(font_size * self.scale_factor);
+        let metrics = self.glyph_cache.metrics(FontStyle::MONOSPACE, size);
+        (
+            metrics.cell_width.round() / self.scale_factor,
+            metrics.line_height.round() / self.scale_factor,
+        )
+    }
+
+    /// width, height and baseline from the top of `text` on a single line
+    /// in `style` at `size` logical pixels, as `draw_spans` would draw it
+    pub fn measure(
+        &mut self,
+        text: &str,
+        style: FontStyle,
+        size: f32,
+    ) -> (f32, f32, f32) {
+        let span = TextSpan::new(text, style, size, Rgb::default());
+        self.measure_spans(&[span])
+    }
+
+    /// width, height and baseline from the top of spans drawn by
+    /// `draw_spans`, in logical pixels
+    pub fn measure_spans(&mut self, spans: &[TextSpan]) -> (f32, f32, f32) {
+        self.measure_wrapped(spans, f32::INFINITY, Alignment::Left)
+    }
+
+    /// width, height and baseline of the first line from the top of spans
+    /// drawn by `draw_wrapped`, in logical pixels
+    pub fn measure_wrapped(
+        &mut self,
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+    ) -> (f32, f32, f32) {
+        let (_, size) = self.cache_text(spans, max_width, alignment);
+        size
+    }
+
+    /// draw a single line of text with the top left corner of its line box
+    /// at `position`, in logical pixels
+    pub fn draw_text(&mut self, text: &str, position: (f32, f32), color: Rgb) {
+        let span =
+            TextSpan::new(text, FontStyle::REGULAR, DEFAULT_FONT_SIZE, color);
+        self.draw_spans(&[span], position);
+    }
+
+    /// draw spans of differently styled text one after another on a single
+    /// line, like `draw_text`. spans share a baseline and the line box fits
+    /// the largest one
+    ///
+    /// the glyphs are kept for the next frames, so drawing the same spans
+    /// again skips shaping and layout
+    pub fn draw_spans(&mut self, spans: &[TextSpan], position: (f32, f32)) {
+        self.draw_wrapped(spans, f32::INFINITY, Alignment::Left, position);
+    }
+
+    /// draw spans broken into lines like `layout` does, with the top left
+    /// corner at `position` in logical pixels. cached like `draw_spans`
+    pub fn draw_wrapped(
+        &mut self,
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+        position: (f32, f32),
+    ) {
+        let (key, _) = self.cache_text(spans, max_width, alignment);
+
+        unsafe {
+            self.begin_layout(position);
+        }
+
+        self.queue_cached(&key);
+        self.render_batch(false);
+
+        unsafe {
+            self.unbind_buffers();
+        }
+    }
+
+    /// break spans into lines no wider than `max_width` logical pixels, or
+    /// only at hard line breaks if it's infinite. each line is as tall as
+    /// the largest span on it
+    pub fn layout(
+        &mut self,
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+    ) -> Layout {
+        Layout::new(
+            &mut self.glyph_cache,
+            spans,
+            max_width * self.scale_factor,
+            alignment,
+            self.scale_factor,
+        )
+    }
+
+    /// draw a line of cells at the default font size, with the top left
+    /// corner of the first one at `position` in logical pixels. cells are
+    /// as wide as a digit and wide graphemes take up two of them, so text
+    /// lines up in columns like in a terminal
+    pub fn draw_cells(&mut self, cells: &[Cell], position: (f32, f32)) {
+        let scale_factor = self.scale_factor;
+        let size = FontSize::new(DEFAULT_FONT_SIZE * scale_factor);
+        let metrics = self.glyph_cache.metrics(FontStyle::REGULAR, size);
+        let (scale_x, scale_y) = self.projection_scale;
+        let position = (position.0 * scale_factor, position.1 * scale_factor);
+
         unsafe {
             gl::UseProgram(self.program.id());
-            gl::Uniform1i(
-                self.program
-                    .get_uniform_location(cstr!("ourTexture"))
-                    .unwrap(),
-                0,
+            self.program.set_projection(
+                -1. + scale_x * position.0,
+                1. + scale_y * position.1,
+                scale_x,
+                scale_y,
+            );
+            self.program.set_cell_dim(
+                metrics.cell_width.round(),
+                metrics.line_height.round(),
+            );
+
+            self.bind_buffers();
+        }
+
+        let descent = metrics.descent.round() as i16;
+        let mut col = 0;
+
+        for cell in cells {
+            let width = cell.width();
+
+            if let Some(bg) = cell.bg {
+                if self.text_batch.full() {
+                    self.render_batch(false);
+                }
+                self.text_batch.add_background(col, 0, bg, width == 2);
+            }
+
+            // graphemes are shaped on their own so nothing spills over into
+            // the next cell
+            let glyphs = self.glyph_cache.shape(
+                cell.text,
+                FontStyle::REGULAR,
+                size,
+                Direction::Auto,
             );
-            gl::BindVertexArray(self.vao);
-            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
-            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+            self.queue_glyphs(&glyphs, 0.0, descent, cell.fg, col, false);
+
+            if !cell.decoration.is_empty() {
+                let right = width as f32 * metrics.cell_width.round();
+                self.queue_decoration(
+                    cell.decoration,
+                    &metrics,
+                    0.0..right,
+                    -descent,
+                    (col, 0),
+                    cell.decoration_color.unwrap_or(cell.fg),
+                );
+            }
+
+            col += width as u16;
+        }
+
+        self.render_batch(false);
+
+        unsafe {
+            self.unbind_buffers();
+        }
+    }
+
+    /// draw the rows of `grid` on screen with the top left corner of the
+    /// first cell at `position`, in logical pixels
+    pub fn draw_grid(&mut self, grid: &CellGrid, position: (f32, f32)) {
+        let scale_factor = self.scale_factor;
+        let size = FontSize::new(grid.font_size() * scale_factor);
+        let metrics = self.glyph_cache.metrics(FontStyle::MONOSPACE, size);
+        let cell_width = metrics.cell_width.round();
+        let (scale_x, scale_y) = self.projection_scale;
+        let position = (position.0 * scale_factor, position.1 * scale_factor);
+
+        unsafe {
+            gl::UseProgram(self.program.id());
+            self.program.set_projection(
+                -1. + scale_x * position.0,
+                1. + scale_y * position.1,
+                scale_x,
+                scale_y,
+            );
+            self.program
+                .set_cell_dim(cell_width, metrics.line_height.round());
+
+            self.bind_buffers();
+        }
+
+        let rows = grid.visible_rows();
+
+        // every background goes in before any glyph, so none of them end up
+        // behind one from a later batch
+        for (row, cells) in rows.iter().enumerate() {
+            let mut col = 0;
+            for cell in cells.iter() {
+                let width = cell.width();
+                if let Some(bg) = cell.bg {
+                    if self.text_batch.full() {
+                        self.render_batch(false);
+                    }
+                    self.text_batch.add_background(
+                        col,
+                        row as u16,
+                        bg,
+                        width == 2,
+                    );
+                }
+                col += width as u16;
+            }
+        }
+
+        let descent = metrics.descent.round() as i16;
+        for (row, cells) in rows.iter().enumerate() {
+            let row = row as u16;
+            let mut col = 0;
+            for cell in cells.iter() {
+                let width = cell.width();
+                // a cell holds a single character, so there's nothing to
+                // shape
+                let key =
+                    self.glyph_cache.glyph_for_cell(cell.c, cell.style(), size);
+                self.queue_glyph(
+                    key,
+                    (0.0, -descent as f32),
+                    (col, row),
+                    cell.fg,
+                    false,
+                );
+
+                if !cell.decoration.is_empty() {
+                    self.queue_decoration(
+                        cell.decoration,
+                        &metrics,
+                        0.0..width as f32 * cell_width,
+                        -descent,
+                        (col, row),
+                        cell.decoration_color.unwrap_or(cell.fg),
+                    );
+                }
+
+                col += width as u16;
+            }
+        }
+
+        self.render_batch(false);
+
+        unsafe {
+            self.unbind_buffers();
+        }
+    }
+
+    /// draw a single line of text at `size` logical pixels like `draw_text`,
+    /// but from distance fields that are generated once and scaled to any
+    /// size, with an optional outline and glow
+    pub fn draw_sdf_text(
+        &mut self,
+        text: &str,
+        position: (f32, f32),
+        size: f32,
+        style: &SdfStyle,
+    ) {
+        let scale_factor = self.scale_factor;
+        let field_size = FontSize::new(SDF_FONT_SIZE);
+        // screen pixels per distance field texel
+        let scale = size * scale_factor / field_size.as_px();
+        let (scale_x, scale_y) = self.projection_scale;
+        let position = (position.0 * scale_factor, position.1 * scale_factor);
+
+        unsafe {
+            gl::UseProgram(self.sdf_program.id());
+            // glyphs are laid out at the size the fields were generated at
+            // and scaled along with everything else
+            self.sdf_program.text.set_projection(
+                -1. + scale_x * position.0,
+                1. + scale_y * position.1,
+                scale_x * scale,
+                scale_y * scale,
+            );
+            self.sdf_program.text.set_cell_dim(1.0, 1.0);
+            self.sdf_program.set_style(style, scale_factor, scale);
+
+            self.bind_buffers();
+        }
+
+        // laid out at the size of the fields, in their pixels
+        let span = TextSpan::new(
+            text,
+            FontStyle::REGULAR,
+            field_size.as_px(),
+            style.color,
+        );
+        let layout = Layout::new(
+            &mut self.glyph_cache,
+            &[span],
+            f32::INFINITY,
+            Alignment::Left,
+            1.0,
+        );
+        self.queue_layout(&layout, true);
+        self.render_batch(true);
+
+        unsafe {
+            self.unbind_buffers();
+        }
+    }
+
+    // set up the text program to draw a layout with its top left corner at
+    // `position` in logical pixels
+    unsafe fn begin_layout(&self, position: (f32, f32)) {
+        let (scale_x, scale_y) = self.projection_scale;
+        // everything from here on is in physical pixels
+        let position = (
+            position.0 * self.scale_factor,
+            position.1 * self.scale_factor,
+        );
+
+        gl::UseProgram(self.program.id());
+        // move the origin to the top of the layout instead of storing the
+        // position in every instance
+        self.program.set_projection(
+            -1. + scale_x * position.0,
+            1. + scale_y * position.1,
+            scale_x,
+            scale_y,
+        );
+        // see `queue_layout`
+        self.program.set_cell_dim(1.0, 1.0);
+
+        self.bind_buffers();
+    }
+
+    // lay out spans and record their instances, unless they're still in the
+    // text cache. returns the key they're cached under along with their
+    // size, see `measure_wrapped`
+    fn cache_text(
+        &mut self,
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+    ) -> (TextKey, (f32, f32, f32)) {
+        let key = TextKey::new(spans, max_width, alignment, self.scale_factor);
+        let generation = self.glyph_cache.generation();
+        if let Some(cached) = self.text_cache.get(&key, generation) {
+            return (key, cached.size);
+        }
+
+        let layout = self.layout(spans, max_width, alignment);
+        let (batches, glyphs) = self.record_layout(&layout);
+        let size = layout.size();
+        self.text_cache
+            .insert(key.clone(), size, batches, glyphs, generation);
+        (key, size)
+    }
+
+    // the instances `queue_layout` adds for `layout`, split wherever it would
+    // have drawn the batch, along with the glyphs they were made from
+    fn record_layout(
+        &mut self,
+        layout: &Layout,
+    ) -> (Vec<RecordedBatch>, Vec<GlyphKey>) {
+        self.recording = Some(Vec::new());
+        self.queue_layout(layout, false);
+        self.render_batch(false);
+        let batches = self.recording.take().unwrap_or_default();
+        (batches, mem::take(&mut self.recorded_glyphs))
+    }
+
+    // add the recorded instances of text cached under `key` to the batch
+    fn queue_cached(&mut self, key: &TextKey) {
+        let generation = self.glyph_cache.generation();
+        let Self {
+            text_cache,
+            text_batch,
+            glyph_cache,
+            program,
+            ..
+        } = self;
+        let Some(cached) = text_cache.get(key, generation) else {
+            return;
+        };
+        // these don't go through `GlyphCache::get`
+        glyph_cache.mark_used(&cached.glyphs);
+
+        for (tex, instances) in &cached.batches {
+            for chunk in instances.chunks(BATCH_MAX) {
+                if text_batch.needs_flush(*tex)
+                    || text_batch.instances.len() + chunk.len() > BATCH_MAX
+                {
+                    unsafe {
+                        glyph_cache.update_textures();
+                        text_batch.render(program);
+                    }
+                }
+                text_batch.add_instances(*tex, chunk);
+            }
+        }
+    }
+
+    // add shaped glyphs to the batch one after another starting at `pen_x`
+    // in cell `col`, returning where the pen ends up
+    fn queue_glyphs(
+        &mut self,
+        glyphs: &[ShapedGlyph],
+        mut pen_x: f32,
+        descent: i16,
+        color: Rgb,
+        col: u16,
+        sdf: bool,
+    ) -> f32 {
+        for shaped in glyphs {
+            let (x, phase) = if sdf {
+                (pen_x + shaped.x_offset, 0)
+            } else {
+                subpixel_phase(pen_x + shaped.x_offset)
+            };
+            let y = shaped.y_offset.round() - descent as f32;
+            pen_x += shaped.x_advance;
+
+            let key = GlyphKey {
+                sdf,
+                phase,
+                ..shaped.key
+            };
+            self.queue_glyph(key, (x, y), (col, 0), color, sdf);
+        }
+
+        pen_x
+    }
+
+    // add every glyph of a layout to the batch, with cells a pixel square so
+    // glyphs in row `y` sit on a baseline at `y + 1`
+    fn queue_layout(&mut self, layout: &Layout, sdf: bool) {
+        for line in &layout.lines {
+            let row = (line.baseline - 1.0).max(0.0) as u16;
+            // backgrounds go in first, glyphs drawn by an earlier batch would
+            // end up behind them
+            for span in &line.spans {
+                let Some(background) = span.background else {
+                    continue;
+                };
+                if self.text_batch.full() {
+                    self.render_batch(sdf);
+                }
+                let left = span.x.start.round();
+                self.text_batch.add_fill(
+                    (0, row),
+                    (left as i16, (line.baseline - line.top) as i16),
+                    ((span.x.end.round() - left) as i16, line.height as i16),
+                    background,
+                );
+            }
+            for glyph in &line.glyphs {
+                let (key, x) = if sdf {
+                    // distance fields are scaled, so they only ever come in
+                    // one phase and get placed to the nearest pixel
+                    let phase = glyph.key.phase as f32 / SUBPIXEL_PHASES as f32;
+                    let key = GlyphKey {
+                        sdf,
+                        phase: 0,
+                        ..glyph.key
+                    };
+                    (key, glyph.x + phase)
+                } else {
+                    (glyph.key, glyph.x)
+                };
+                self.queue_glyph(key, (x, glyph.y), (0, row), glyph.color, sdf);
+            }
+            for span in &line.spans {
+                let metrics = self.glyph_cache.metrics(span.style, span.size);
+                self.queue_decoration(
+                    span.decoration,
+                    &metrics,
+                    span.x.clone(),
+                    0,
+                    (0, row),
+                    span.decoration_color,
+                );
+            }
+        }
+    }
+
+    // add the lines of a decoration for text with `metrics` to the batch,
+    // spanning `x` pixels from the left of `cell` along a baseline
+    // `baseline` pixels up from its bottom
+    fn queue_decoration(
+        &mut self,
+        decoration: Decoration,
+        metrics: &LineMetrics,
+        x: Range<f32>,
+        baseline: i16,
+        cell: (u16, u16),
+        color: Rgb,
+    ) {
+        let left = x.start.round();
+        let width = (x.end.round() - left) as i16;
+        for mut line in decoration.lines(metrics) {
+            if self.text_batch.full() {
+                // distance fields never have decorations
+                self.render_batch(false);
+            }
+            line.top += baseline;
+            self.text_batch.add_decoration(
+                cell.0,
+                cell.1,
+                left as i16,
+                width,
+                &line,
+                color,
+            );
+        }
+    }
+
+    // add a glyph to the batch with its origin at `position` pixels from the
+    // bottom left corner of `cell`, with y pointing up
+    fn queue_glyph(
+        &mut self,
+        key: GlyphKey,
+        position: (f32, f32),
+        cell: (u16, u16),
+        color: Rgb,
+        sdf: bool,
+    ) {
+        let mut glyph = match self.glyph_cache.get(key) {
+            Ok(glyph) => glyph,
+            Err(_) => return,
+        };
+        if self.recording.is_some() {
+            self.recorded_glyphs.push(key);
+        }
+
+        // nothing to draw for whitespace
+        if glyph.width == 0 || glyph.height == 0 {
+            return;
+        }
+
+        if self.text_batch.needs_flush(glyph.tex_id) {
+            self.render_batch(sdf);
+        }
+
+        glyph.left += position.0.round() as i16;
+        glyph.top += position.1.round() as i16;
+        self.text_batch.add_item(cell.0, cell.1, &glyph, color);
+    }
+
+    fn render_batch(&mut self, sdf: bool) {
+        if let Some(batches) = &mut self.recording {
+            if !self.text_batch.is_empty() {
+                let instances = self.text_batch.instances.drain(..).collect();
+                batches.push((self.text_batch.tex, instances));
+                self.text_batch.clear();
+            }
+            return;
+        }
+
+        let program = if sdf {
+            &self.sdf_program.text
+        } else {
+            &self.program
+        };
+
+        unsafe {
+            // glyphs in the batch may not have been uploaded yet
+            self.glyph_cache.update_textures();
+            self.text_batch.render(program);
+        }
+    }
+
+    unsafe fn bind_buffers(&self) {
+        gl::BindVertexArray(self.vao);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo);
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo_instance);
+    }
+
+    unsafe fn unbind_buffers(&self) {
+        gl::BindVertexArray(0);
+        gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
+        gl::UseProgram(0);
+    }
+}
+
+unsafe fn set_blend_func(antialiasing: Antialiasing) {
+    match antialiasing {
+        Antialiasing::Grayscale => {
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA)
+        }
+        // dual source blending, the shader's second output holds separate
+        // coverage for each channel
+        Antialiasing::Subpixel => {
+            gl::BlendFunc(gl::SRC1_COLOR, gl::ONE_MINUS_SRC1_COLOR)
         }
     }
 }
@@ -331,36 +1248,119 @@ impl Renderer {
 impl Drop for Renderer {
     fn drop(&mut self) {
         unsafe {
-            gl::DeleteBuffers(1, &self.vbo);
-            // gl::DeleteBuffers(1, &self.ebo);
+            gl::DeleteBuffers(1, &self.vbo_instance);
+            gl::DeleteBuffers(1, &self.ebo);
             gl::DeleteVertexArrays(1, &self.vao);
         }
     }
 }
 
-// #[derive(Debug)]
-// pub struct TextShaderProgram {
-//     program: ShaderProgram,
-//     u_projection: GLint,
-//     u_rendering_pass: GLint,
-// }
-
-// impl TextShaderProgram {
-//     pub fn new() -> Result<Self, ShaderError> {
-//         let program = ShaderProgram::new(TEXT_SHADER_V, TEXT_SHADER_F)?;
-//         Ok(Self {
-//             u_projection: program.get_uniform_location(cstr!("projection"))?,
-//             u_rendering_pass: program
-//                 .get_uniform_location(cstr!("renderingPass"))?,
-//             program,
-//         })
-//     }
-
-//     pub fn id(&self) -> GLuint {
-//         self.program.id()
-//     }
-
-//     unsafe fn set_rendering_pass(&self, rendering_pass: RenderingPass) {
-//         gl::Uniform1i(self.u_rendering_pass, rendering_pass as i32);
-//     }
-// }
+#[derive(Debug)]
+pub struct TextShaderProgram {
+    program: ShaderProgram,
+    u_projection: GLint,
+    u_cell_dim: GLint,
+    u_rendering_pass: GLint,
+}
+
+impl TextShaderProgram {
+    pub fn new() -> Result<Self, ShaderError> {
+        Self::with_fragment_shader(TEXT_SHADER_F)
+    }
+
+    // the text vertex shader along with a different fragment shader
+    fn with_fragment_shader(
+        fragment_shader: &'static str,
+    ) -> Result<Self, ShaderError> {
+        let program = ShaderProgram::new(TEXT_SHADER_V, fragment_shader)?;
+        Ok(Self {
+            u_projection: program.get_uniform_location(cstr!("projection"))?,
+            u_cell_dim: program.get_uniform_location(cstr!("cellDim"))?,
+            u_rendering_pass: program
+                .get_uniform_location(cstr!("renderingPass"))?,
+            program,
+        })
+    }
+
+    pub fn id(&self) -> GLuint {
+        self.program.id()
+    }
+
+    unsafe fn set_projection(
+        &self,
+        offset_x: f32,
+        offset_y: f32,
+        scale_x: f32,
+        scale_y: f32,
+    ) {
+        gl::Uniform4f(self.u_projection, offset_x, offset_y, scale_x, scale_y);
+    }
+
+    unsafe fn set_cell_dim(&self, width: f32, height: f32) {
+        gl::Uniform2f(self.u_cell_dim, width, height);
+    }
+
+    unsafe fn set_rendering_pass(&self, rendering_pass: RenderingPass) {
+        gl::Uniform1i(self.u_rendering_pass, rendering_pass as i32);
+    }
+}
+
+#[derive(Debug)]
+pub struct SdfShaderProgram {
+    text: TextShaderProgram,
+    u_sdf_range: GLint,
+    u_sdf_scale: GLint,
+    u_outline_color: GLint,
+    u_outline_width: GLint,
+    u_glow_color: GLint,
+    u_glow_width: GLint,
+}
+
+impl SdfShaderProgram {
+    pub fn new() -> Result<Self, ShaderError> {
+        let text = TextShaderProgram::with_fragment_shader(SDF_SHADER_F)?;
+        let program = &text.program;
+        Ok(Self {
+            u_sdf_range: program.get_uniform_location(cstr!("sdfRange"))?,
+            u_sdf_scale: program.get_uniform_location(cstr!("sdfScale"))?,
+            u_outline_color: program
+                .get_uniform_location(cstr!("outlineColor"))?,
+            u_outline_width: program
+                .get_uniform_location(cstr!("outlineWidth"))?,
+            u_glow_color: program.get_uniform_location(cstr!("glowColor"))?,
+            u_glow_width: program.get_uniform_location(cstr!("glowWidth"))?,
+            text,
+        })
+    }
+
+    pub fn id(&self) -> GLuint {
+        self.text.id()
+    }
+
+    // `scale` is screen pixels per distance field texel
+    unsafe fn set_style(
+        &self,
+        style: &SdfStyle,
+        scale_factor: f32,
+        scale: f32,
+    ) {
+        let color = |rgb: Rgb| {
+            (
+                rgb.r as f32 / 255.,
+                rgb.g as f32 / 255.,
+                rgb.b as f32 / 255.,
+            )
+        };
+
+        gl::Uniform1f(self.u_sdf_range, SDF_RANGE);
+        gl::Uniform1f(self.u_sdf_scale, scale);
+
+        let (r, g, b) = color(style.outline_color);
+        gl::Uniform3f(self.u_outline_color, r, g, b);
+        gl::Uniform1f(self.u_outline_width, style.outline_width * scale_factor);
+
+        let (r, g, b) = color(style.glow_color);
+        gl::Uniform3f(self.u_glow_color, r, g, b);
+        gl::Uniform1f(self.u_glow_width, style.glow_width * scale_factor);
+    }
+}
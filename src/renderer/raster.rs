@@ -0,0 +1,332 @@
+use std::slice;
+
+use font_kit::font::Font;
+use freetype::freetype::*;
+use image::{imageops, imageops::FilterType, RgbaImage};
+use pathfinder_geometry::{rect::RectI, vector::Vector2I};
+
+use super::glyph_cache::{Antialiasing, Hinting, RasterOptions};
+
+// the FT_LOAD_TARGET_* flags are C macros so the bindings don't have them
+const FT_LOAD_TARGET_NORMAL: u32 =
+    load_target(FT_Render_Mode::FT_RENDER_MODE_NORMAL);
+const FT_LOAD_TARGET_LIGHT: u32 =
+    load_target(FT_Render_Mode::FT_RENDER_MODE_LIGHT);
+const FT_LOAD_TARGET_LCD: u32 = load_target(FT_Render_Mode::FT_RENDER_MODE_LCD);
+
+const fn load_target(mode: FT_Render_Mode) -> u32 {
+    (mode as u32 & 15) << 16
+}
+
+/// A glyph rasterized directly through FreeType, with tightly packed rows of
+/// premultiplied RGBA, RGB subpixel coverage or grayscale coverage
+pub struct RasterizedGlyph {
+    pub bounds: RectI,
+    pub pixels: Vec<u8>,
+}
+
+/// whether the font has any color tables (CBDT, sbix, COLR, ...)
+pub fn has_color(font: &Font) -> bool {
+    let face = font.native_font();
+    unsafe {
+        let colored = (*face).face_flags & FT_FACE_FLAG_COLOR as FT_Long != 0;
+        FT_Done_Face(face);
+        colored
+    }
+}
+
+/// rasterize the color version of a glyph at `font_size` pixels
+///
+/// returns `None` if the glyph has no color data, in which case it should be
+/// rasterized as a regular mask instead. font-kit can't do this itself since
+/// it never asks FreeType for color and panics on bitmap-only fonts
+pub fn rasterize_color(
+    font: &Font,
+    glyph_id: u32,
+    font_size: f32,
+) -> Option<RasterizedGlyph> {
+    with_face(font, font_size, |face, scale| unsafe {
+        load_color(face, glyph_id, scale)
+    })
+}
+
+/// rasterize a glyph's coverage at `font_size` pixels, grayscale or per
+/// channel for LCD subpixel rendering depending on `options`. outlines are
+/// moved `offset` pixels to the right first, for subpixel positioning
+///
+/// font-kit can't embolden outlines, and its own LCD path treats FreeType's
+/// triple width bitmaps as RGB pixels and reads past the end of each row, so
+/// this is done by hand
+pub fn rasterize_mask(
+    font: &Font,
+    glyph_id: u32,
+    font_size: f32,
+    offset: f32,
+    options: &RasterOptions,
+) -> Option<RasterizedGlyph> {
+    let mut glyph = with_face(font, font_size, |face, _| unsafe {
+        load_mask(face, glyph_id, font_size, offset, options)
+    })?;
+
+    if options.gamma != 1.0 || options.contrast != 0.0 {
+        let table = coverage_table(options.gamma, options.contrast);
+        for coverage in &mut glyph.pixels {
+            *coverage = table[*coverage as usize];
+        }
+    }
+
+    Some(glyph)
+}
+
+// maps coverage through gamma, then blends it towards a smoothstep of itself
+// by `contrast`, which keeps it fully on or off at either end
+fn coverage_table(gamma: f32, contrast: f32) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let coverage = (i as f32 / 255.0).powf(1.0 / gamma);
+        let steep = coverage * coverage * (3.0 - 2.0 * coverage);
+        let coverage = coverage + (steep - coverage) * contrast;
+        *entry = (coverage.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    table
+}
+
+// how far outlines are pushed out in pixels by stem darkening. thin stems at
+// small sizes wash out the most, so they get the most
+fn stem_darkening(font_size: f32) -> f32 {
+    (0.5 - font_size / 96.0).clamp(0.1, 0.4)
+}
+
+// run `f` with the font's face sized for `font_size`, along with how much the
+// resulting bitmaps still need to be scaled by
+fn with_face<F>(font: &Font, font_size: f32, f: F) -> Option<RasterizedGlyph>
+where
+    F: FnOnce(FT_Face, f32) -> Option<RasterizedGlyph>,
+{
+    // takes a reference which is released at the end
+    let face = font.native_font();
+
+    unsafe {
+        let scale = set_size(face, font_size);
+        let glyph = scale.and_then(|scale| f(face, scale));
+
+        // font-kit expects the face to be left at its default size
+        if (*face).face_flags & FT_FACE_FLAG_SCALABLE as FT_Long != 0 {
+            FT_Set_Char_Size(
+                face,
+                ((*face).units_per_EM as FT_F26Dot6) << 6,
+                0,
+                0,
+                0,
+            );
+        }
+        FT_Done_Face(face);
+
+        glyph
+    }
+}
+
+// size the face for `font_size`, returning how much the loaded bitmaps still
+// need to be scaled by
+unsafe fn set_size(face: FT_Face, font_size: f32) -> Option<f32> {
+    if (*face).face_flags & FT_FACE_FLAG_SCALABLE as FT_Long != 0 {
+        let size = (font_size * 64.0) as FT_F26Dot6;
+        return match FT_Set_Char_Size(face, size, 0, 0, 0) {
+            0 => Some(1.0),
+            _ => None,
+        };
+    }
+
+    // bitmap-only fonts (e.g. Noto Color Emoji) come in fixed strikes, so
+    // pick the smallest one that's at least as big as we need and scale down
+    let strikes = slice::from_raw_parts(
+        (*face).available_sizes,
+        (*face).num_fixed_sizes as usize,
+    );
+    let ppem = |strike: &FT_Bitmap_Size| strike.y_ppem as f32 / 64.0;
+    let (index, strike) = strikes
+        .iter()
+        .enumerate()
+        .filter(|(_, strike)| ppem(strike) >= font_size)
+        .min_by(|(_, a), (_, b)| ppem(a).total_cmp(&ppem(b)))
+        .or_else(|| {
+            strikes
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| ppem(a).total_cmp(&ppem(b)))
+        })?;
+
+    match FT_Select_Size(face, index as FT_Int) {
+        0 => Some(font_size / ppem(strike)),
+        _ => None,
+    }
+}
+
+unsafe fn load_mask(
+    face: FT_Face,
+    glyph_id: u32,
+    font_size: f32,
+    offset: f32,
+    options: &RasterOptions,
+) -> Option<RasterizedGlyph> {
+    let lcd = options.antialiasing == Antialiasing::Subpixel;
+    let hinting = match options.hinting {
+        Hinting::None => FT_LOAD_NO_HINTING,
+        Hinting::Vertical => FT_LOAD_TARGET_LIGHT,
+        Hinting::Full if lcd => FT_LOAD_TARGET_LCD,
+        Hinting::Full => FT_LOAD_TARGET_NORMAL,
+    };
+    if FT_Load_Glyph(face, glyph_id, (FT_LOAD_DEFAULT | hinting) as FT_Int32)
+        != 0
+    {
+        return None;
+    }
+
+    let slot = (*face).glyph;
+    let outline = (*slot).format == FT_Glyph_Format::FT_GLYPH_FORMAT_OUTLINE;
+    if options.stem_darkening && outline {
+        // stems are mostly vertical, so mostly widen them
+        let strength = stem_darkening(font_size) * 64.0;
+        FT_Outline_EmboldenXY(
+            &mut (*slot).outline,
+            strength as FT_Pos,
+            (strength / 2.0) as FT_Pos,
+        );
+    }
+    // after hinting, which would snap it back to the pixel grid
+    if outline && offset != 0.0 {
+        FT_Outline_Translate(&(*slot).outline, (offset * 64.0) as FT_Pos, 0);
+    }
+
+    let mode = if lcd {
+        // only fails if FreeType was built without filtering, which is fine
+        FT_Library_SetLcdFilter(
+            (*slot).library,
+            FT_LcdFilter::FT_LCD_FILTER_DEFAULT,
+        );
+        FT_Render_Mode::FT_RENDER_MODE_LCD
+    } else {
+        FT_Render_Mode::FT_RENDER_MODE_NORMAL
+    };
+    // bitmap glyphs are already rendered
+    if outline && FT_Render_Glyph(slot, mode) != 0 {
+        return None;
+    }
+
+    let bitmap = &(*slot).bitmap;
+    let height = bitmap.rows as usize;
+    let pitch = bitmap.pitch.unsigned_abs() as usize;
+    // blank glyphs like spaces come without a buffer and a pitch of 0
+    if bitmap.buffer.is_null() || pitch == 0 || height == 0 {
+        let bounds = RectI::new(
+            Vector2I::new((*slot).bitmap_left, -(*slot).bitmap_top),
+            Vector2I::zero(),
+        );
+        return Some(RasterizedGlyph {
+            bounds,
+            pixels: Vec::new(),
+        });
+    }
+    let rows = slice::from_raw_parts(bitmap.buffer, pitch * height);
+
+    // one byte per pixel, or three for subpixel coverage
+    let mut width = bitmap.width as usize;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    let mode = bitmap.pixel_mode;
+    if mode == FT_Pixel_Mode::FT_PIXEL_MODE_LCD as u8 {
+        width /= 3;
+        for row in rows.chunks_exact(pitch) {
+            pixels.extend_from_slice(&row[..width * 3]);
+        }
+    } else if mode == FT_Pixel_Mode::FT_PIXEL_MODE_GRAY as u8 {
+        for row in rows.chunks_exact(pitch) {
+            pixels.extend_from_slice(&row[..width]);
+        }
+    } else if mode == FT_Pixel_Mode::FT_PIXEL_MODE_MONO as u8 {
+        // a bit per pixel, from bitmap-only fonts
+        for row in rows.chunks_exact(pitch) {
+            pixels.extend(
+                (0..width).map(|x| (row[x / 8] >> (7 - x % 8) & 1) * 255),
+            );
+        }
+    } else {
+        return None;
+    }
+
+    // bitmap strikes aren't filtered per channel
+    if lcd && mode != FT_Pixel_Mode::FT_PIXEL_MODE_LCD as u8 {
+        pixels = pixels.iter().flat_map(|&c| [c, c, c]).collect();
+    }
+
+    let bounds = RectI::new(
+        Vector2I::new((*slot).bitmap_left, -(*slot).bitmap_top),
+        Vector2I::new(width as i32, height as i32),
+    );
+    Some(RasterizedGlyph { bounds, pixels })
+}
+
+unsafe fn load_color(
+    face: FT_Face,
+    glyph_id: u32,
+    scale: f32,
+) -> Option<RasterizedGlyph> {
+    let flags = FT_LOAD_DEFAULT | FT_LOAD_RENDER | FT_LOAD_COLOR;
+    if FT_Load_Glyph(face, glyph_id, flags as FT_Int32) != 0 {
+        return None;
+    }
+
+    let slot = (*face).glyph;
+    let bitmap = &(*slot).bitmap;
+    if bitmap.pixel_mode != FT_Pixel_Mode::FT_PIXEL_MODE_BGRA as u8 {
+        return None;
+    }
+
+    let width = bitmap.width;
+    let height = bitmap.rows;
+    let pitch = bitmap.pitch.unsigned_abs() as usize;
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    if !bitmap.buffer.is_null() {
+        let buffer =
+            slice::from_raw_parts(bitmap.buffer, pitch * height as usize);
+        for row in buffer.chunks_exact(pitch) {
+            // already premultiplied, just needs swizzling
+            for bgra in row[..width as usize * 4].chunks_exact(4) {
+                pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+            }
+        }
+    }
+
+    let left = (*slot).bitmap_left;
+    let top = (*slot).bitmap_top;
+
+    if scale == 1.0 || pixels.is_empty() {
+        let bounds = RectI::new(
+            Vector2I::new(left, -top),
+            Vector2I::new(width as i32, height as i32),
+        );
+        return Some(RasterizedGlyph { bounds, pixels });
+    }
+
+    let image = RgbaImage::from_raw(width, height, pixels)?;
+    let scaled_width = ((width as f32 * scale).round() as u32).max(1);
+    let scaled_height = ((height as f32 * scale).round() as u32).max(1);
+    // filtering premultiplied pixels directly is what we want here
+    let image = imageops::resize(
+        &image,
+        scaled_width,
+        scaled_height,
+        FilterType::Triangle,
+    );
+
+    let bounds = RectI::new(
+        Vector2I::new(
+            (left as f32 * scale).round() as i32,
+            -(top as f32 * scale).round() as i32,
+        ),
+        Vector2I::new(scaled_width as i32, scaled_height as i32),
+    );
+    Some(RasterizedGlyph {
+        bounds,
+        pixels: image.into_raw(),
+    })
+}
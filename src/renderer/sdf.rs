@@ -0,0 +1,205 @@
+use std::ptr;
+
+use font_kit::{font::Font, hinting::HintingOptions, outline::OutlineSink};
+use freetype::freetype::*;
+use pathfinder_geometry::{
+    line_segment::LineSegment2F,
+    rect::RectI,
+    vector::{Vector2F, Vector2I},
+};
+
+use super::raster::RasterizedGlyph;
+
+/// How far from the outline, in pixels at the size the field was generated
+/// at, distances are stored. outlines and glows can't be wider than this
+pub const SDF_RANGE: f32 = 8.0;
+
+// line segments each curve is flattened into
+const CURVE_STEPS: u32 = 8;
+
+/// generate a signed distance field for a glyph from its outline, as if it
+/// was rasterized at `font_size` pixels
+///
+/// each byte of the single channel result maps distances from -`SDF_RANGE`
+/// (outside) to `SDF_RANGE` (inside) onto 0 to 255, with the outline at 128.
+/// bounds include `SDF_RANGE` pixels of room around the outline
+pub fn rasterize_sdf(
+    font: &Font,
+    glyph_id: u32,
+    font_size: f32,
+) -> Option<RasterizedGlyph> {
+    reset_face(font);
+
+    let units_per_em = font.metrics().units_per_em as f32;
+    let mut flattener = Flattener::new(font_size / units_per_em);
+    font.outline(glyph_id, HintingOptions::None, &mut flattener)
+        .ok()?;
+    let segments = flattener.segments;
+
+    // nothing to draw, e.g. for spaces
+    if segments.is_empty() {
+        return Some(RasterizedGlyph {
+            bounds: RectI::default(),
+            pixels: Vec::new(),
+        });
+    }
+
+    let (min, max) = segments.iter().fold(
+        (Vector2F::splat(f32::MAX), Vector2F::splat(f32::MIN)),
+        |(min, max), segment| {
+            (
+                min.min(segment.from()).min(segment.to()),
+                max.max(segment.from()).max(segment.to()),
+            )
+        },
+    );
+    let range = Vector2F::splat(SDF_RANGE);
+    let origin = (min - range).floor().to_i32();
+    let size = (max + range).ceil().to_i32() - origin;
+    let bounds = RectI::new(origin, size);
+
+    let mut pixels = Vec::with_capacity((size.x() * size.y()) as usize);
+    for y in bounds.min_y()..bounds.max_y() {
+        for x in bounds.min_x()..bounds.max_x() {
+            let center = Vector2I::new(x, y).to_f32() + Vector2F::splat(0.5);
+            let distance = signed_distance(&segments, center);
+            let value = 0.5 + distance / (2.0 * SDF_RANGE);
+            pixels.push((value.clamp(0.0, 1.0) * 255.0).round() as u8);
+        }
+    }
+
+    Some(RasterizedGlyph { bounds, pixels })
+}
+
+// font-kit loads outlines with whatever size and transform the face was left
+// with by the last glyph it rasterized, which is only in font units if it's
+// put back to the defaults first
+fn reset_face(font: &Font) {
+    // takes a reference which is released at the end
+    let face = font.native_font();
+
+    unsafe {
+        FT_Set_Transform(face, ptr::null_mut(), ptr::null_mut());
+        if (*face).face_flags & FT_FACE_FLAG_SCALABLE as FT_Long != 0 {
+            FT_Set_Char_Size(
+                face,
+                ((*face).units_per_EM as FT_F26Dot6) << 6,
+                0,
+                0,
+                0,
+            );
+        }
+        FT_Done_Face(face);
+    }
+}
+
+// distance from `point` to the closest segment, positive inside the outline
+fn signed_distance(segments: &[LineSegment2F], point: Vector2F) -> f32 {
+    let mut closest = f32::MAX;
+    let mut winding = 0;
+
+    for segment in segments {
+        let (from, to) = (segment.from(), segment.to());
+
+        // nonzero winding rule, counting edges crossing the ray to the right
+        // of the point
+        let side = (to - from).det(point - from);
+        if from.y() <= point.y() && to.y() > point.y() && side > 0.0 {
+            winding += 1;
+        } else if to.y() <= point.y() && from.y() > point.y() && side < 0.0 {
+            winding -= 1;
+        }
+
+        closest = closest.min(distance_squared(segment, point));
+    }
+
+    let distance = closest.sqrt();
+    if winding != 0 {
+        distance
+    } else {
+        -distance
+    }
+}
+
+fn distance_squared(segment: &LineSegment2F, point: Vector2F) -> f32 {
+    let vector = segment.vector();
+    let length_squared = vector.square_length();
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        ((point - segment.from()).dot(vector) / length_squared).clamp(0.0, 1.0)
+    };
+
+    (segment.from() + vector * t - point).square_length()
+}
+
+// collects an outline as line segments in pixels, with y pointing down
+struct Flattener {
+    scale: f32,
+    segments: Vec<LineSegment2F>,
+    start: Vector2F,
+    current: Vector2F,
+}
+
+impl Flattener {
+    fn new(scale: f32) -> Self {
+        Self {
+            scale,
+            segments: Vec::new(),
+            start: Vector2F::zero(),
+            current: Vector2F::zero(),
+        }
+    }
+
+    fn to_pixels(&self, point: Vector2F) -> Vector2F {
+        Vector2F::new(point.x(), -point.y()) * self.scale
+    }
+
+    fn push(&mut self, to: Vector2F) {
+        if to != self.current {
+            self.segments.push(LineSegment2F::new(self.current, to));
+        }
+        self.current = to;
+    }
+}
+
+impl OutlineSink for Flattener {
+    fn move_to(&mut self, to: Vector2F) {
+        self.close();
+        self.start = self.to_pixels(to);
+        self.current = self.start;
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        self.push(self.to_pixels(to));
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        let from = self.current;
+        let (ctrl, to) = (self.to_pixels(ctrl), self.to_pixels(to));
+        for i in 1..=CURVE_STEPS {
+            let t = i as f32 / CURVE_STEPS as f32;
+            self.push(from.lerp(ctrl, t).lerp(ctrl.lerp(to, t), t));
+        }
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        let from = self.current;
+        let (ctrl0, ctrl1) =
+            (self.to_pixels(ctrl.from()), self.to_pixels(ctrl.to()));
+        let to = self.to_pixels(to);
+        for i in 1..=CURVE_STEPS {
+            let t = i as f32 / CURVE_STEPS as f32;
+            let a = from.lerp(ctrl0, t);
+            let b = ctrl0.lerp(ctrl1, t);
+            let c = ctrl1.lerp(to, t);
+            self.push(a.lerp(b, t).lerp(b.lerp(c, t), t));
+        }
+    }
+
+    // contours aren't always closed explicitly, the winding count needs them
+    // to be
+    fn close(&mut self) {
+        self.push(self.start);
+    }
+}
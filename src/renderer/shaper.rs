@@ -0,0 +1,153 @@
+use std::os::raw::{c_char, c_int, c_uint};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use harfbuzz_sys::*;
+
+use super::glyph_cache::{FontKey, FontSize, GlyphKey};
+
+/// A glyph positioned by HarfBuzz, in pixels with y pointing up
+#[derive(Copy, Clone, Debug)]
+pub struct ShapedGlyph {
+    pub key: GlyphKey,
+    pub x_advance: f32,
+    pub x_offset: f32,
+    pub y_offset: f32,
+    // byte offset of the first character the glyph was shaped from
+    pub cluster: usize,
+}
+
+/// Which way a run of text goes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // guessed from the text, fine for runs that don't mix directions
+    Auto,
+    LeftToRight,
+    RightToLeft,
+}
+
+/// HarfBuzz font and reusable buffer for shaping runs of text in one font
+pub struct Shaper {
+    key: FontKey,
+    // harfbuzz reads from this directly, so it has to outlive the font
+    _data: Arc<Vec<u8>>,
+    font: *mut hb_font_t,
+    buffer: *mut hb_buffer_t,
+    units_per_em: f32,
+}
+
+impl Shaper {
+    pub fn new(
+        key: FontKey,
+        data: Arc<Vec<u8>>,
+        font_index: u32,
+        units_per_em: u32,
+    ) -> Self {
+        unsafe {
+            let blob = hb_blob_create(
+                data.as_ptr() as *const c_char,
+                data.len() as c_uint,
+                HB_MEMORY_MODE_READONLY,
+                ptr::null_mut(),
+                None,
+            );
+            let face = hb_face_create(blob, font_index);
+            let font = hb_font_create(face);
+
+            // the font holds its own references to these
+            hb_face_destroy(face);
+            hb_blob_destroy(blob);
+
+            // keep positions in font units and scale them per call, so the
+            // same shaper can be used at any size
+            hb_font_set_scale(
+                font,
+                units_per_em as c_int,
+                units_per_em as c_int,
+            );
+
+            Self {
+                key,
+                _data: data,
+                font,
+                buffer: hb_buffer_create(),
+                units_per_em: units_per_em as f32,
+            }
+        }
+    }
+
+    /// shape `text` at `size`, returning glyphs in visual order
+    pub fn shape(
+        &mut self,
+        text: &str,
+        size: FontSize,
+        direction: Direction,
+    ) -> Vec<ShapedGlyph> {
+        let scale = size.as_px() / self.units_per_em;
+
+        unsafe {
+            hb_buffer_clear_contents(self.buffer);
+            hb_buffer_add_utf8(
+                self.buffer,
+                text.as_ptr() as *const c_char,
+                text.len() as c_int,
+                0,
+                text.len() as c_int,
+            );
+            // picks direction, script and language from the text itself
+            hb_buffer_guess_segment_properties(self.buffer);
+            // bidi resolution knows better for neutral characters, like
+            // which way brackets face
+            match direction {
+                Direction::Auto => (),
+                Direction::LeftToRight => {
+                    hb_buffer_set_direction(self.buffer, HB_DIRECTION_LTR)
+                }
+                Direction::RightToLeft => {
+                    hb_buffer_set_direction(self.buffer, HB_DIRECTION_RTL)
+                }
+            }
+
+            hb_shape(self.font, self.buffer, ptr::null(), 0);
+
+            let mut len = 0;
+            let infos = hb_buffer_get_glyph_infos(self.buffer, &mut len);
+            let positions =
+                hb_buffer_get_glyph_positions(self.buffer, &mut len);
+            if len == 0 {
+                return Vec::new();
+            }
+
+            let infos = slice::from_raw_parts(infos, len as usize);
+            let positions = slice::from_raw_parts(positions, len as usize);
+
+            infos
+                .iter()
+                .zip(positions)
+                .map(|(info, pos)| ShapedGlyph {
+                    key: GlyphKey {
+                        font: self.key,
+                        glyph_id: info.codepoint,
+                        size,
+                        sdf: false,
+                        phase: 0,
+                    },
+                    x_advance: pos.x_advance as f32 * scale,
+                    x_offset: pos.x_offset as f32 * scale,
+                    y_offset: pos.y_offset as f32 * scale,
+                    cluster: info.cluster as usize,
+                })
+                .collect()
+        }
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        unsafe {
+            hb_buffer_destroy(self.buffer);
+            hb_font_destroy(self.font);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+use fnv::FnvHasher;
+use gl::types::GLuint;
+
+use super::{
+    Alignment, Decoration, FontStyle, GlyphKey, InstanceData, Rgb, TextSpan,
+};
+
+// entries that haven't been drawn or measured in this many frames are
+// dropped
+const MAX_UNUSED_FRAMES: u64 = 60;
+
+/// Instances sampling the same texture, in the order they're drawn
+pub(super) type RecordedBatch = (GLuint, Vec<InstanceData>);
+
+// a span with its size as bits, so it can be hashed
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SpanKey {
+    text: String,
+    style: FontStyle,
+    size: u32,
+    color: Rgb,
+    background: Option<Rgb>,
+    decoration: Decoration,
+    decoration_color: Option<Rgb>,
+}
+
+/// Everything text is laid out and drawn from
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) struct TextKey {
+    spans: Vec<SpanKey>,
+    max_width: u32,
+    alignment: Alignment,
+    scale_factor: u32,
+}
+
+impl TextKey {
+    pub fn new(
+        spans: &[TextSpan],
+        max_width: f32,
+        alignment: Alignment,
+        scale_factor: f32,
+    ) -> Self {
+        let spans = spans
+            .iter()
+            .map(|span| SpanKey {
+                text: span.text.into(),
+                style: span.style,
+                size: span.size.to_bits(),
+                color: span.color,
+                background: span.background,
+                decoration: span.decoration,
+                decoration_color: span.decoration_color,
+            })
+            .collect();
+        Self {
+            spans,
+            max_width: max_width.to_bits(),
+            alignment,
+            scale_factor: scale_factor.to_bits(),
+        }
+    }
+}
+
+/// Text that was laid out before, ready to be copied into a batch
+pub(super) struct CachedText {
+    // width, height and baseline, see `Layout::size`
+    pub size: (f32, f32, f32),
+    pub batches: Vec<RecordedBatch>,
+    // every glyph the instances were made from, to keep them from being
+    // evicted while the text is drawn from the cache
+    pub glyphs: Vec<GlyphKey>,
+    // of the glyph cache the instances were made with, see
+    // `GlyphCache::generation`
+    generation: u64,
+    last_used: u64,
+}
+
+/// Glyph instances for recently drawn text, so text that stays the same from
+/// one frame to the next isn't shaped and laid out again every time
+#[derive(Default)]
+pub(super) struct TextCache {
+    entries: HashMap<TextKey, CachedText, BuildHasherDefault<FnvHasher>>,
+    // bumped by `end_frame`
+    frame: u64,
+}
+
+impl TextCache {
+    /// the text for `key`, unless its instances are from another
+    /// generation of the glyph cache
+    pub fn get(
+        &mut self,
+        key: &TextKey,
+        generation: u64,
+    ) -> Option<&CachedText> {
+        let cached = self.entries.get_mut(key)?;
+        if cached.generation != generation {
+            return None;
+        }
+
+        cached.last_used = self.frame;
+        Some(cached)
+    }
+
+    pub fn insert(
+        &mut self,
+        key: TextKey,
+        size: (f32, f32, f32),
+        batches: Vec<RecordedBatch>,
+        glyphs: Vec<GlyphKey>,
+        generation: u64,
+    ) {
+        let cached = CachedText {
+            size,
+            batches,
+            glyphs,
+            generation,
+            last_used: self.frame,
+        };
+        self.entries.insert(key, cached);
+    }
+
+    /// called once everything has been drawn, text that hasn't been used in
+    /// a while is dropped
+    pub fn end_frame(&mut self) {
+        let frame = self.frame;
+        self.entries
+            .retain(|_, cached| frame - cached.last_used < MAX_UNUSED_FRAMES);
+        self.frame += 1;
+    }
+}